@@ -0,0 +1,459 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine as _};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Who a connection authenticated as.
+#[derive(Debug, Clone)]
+pub struct ClientIdentity {
+    pub client_id: String,
+}
+
+pub enum AuthOutcome {
+    Accepted(ClientIdentity),
+    /// The credential is meant for this authenticator but is not valid.
+    Rejected(String),
+    /// The credential is not in a form this authenticator understands.
+    NotApplicable,
+}
+
+/// A way for clients to prove they may use the server.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, credential: &str) -> AuthOutcome;
+}
+
+/// Accepts a fixed set of API keys from the server configuration.
+pub struct ApiKeyAuthenticator {
+    keys: Vec<String>,
+}
+
+impl ApiKeyAuthenticator {
+    pub fn new(keys: Vec<String>) -> Self {
+        Self { keys }
+    }
+}
+
+impl Authenticator for ApiKeyAuthenticator {
+    fn authenticate(&self, credential: &str) -> AuthOutcome {
+        // Check every key so the time taken doesn't reveal which one came close
+        let matched = self
+            .keys
+            .iter()
+            .enumerate()
+            .fold(None, |matched, (index, key)| {
+                if constant_time_eq(key.as_bytes(), credential.as_bytes()) {
+                    Some(index)
+                } else {
+                    matched
+                }
+            });
+
+        match matched {
+            Some(index) => AuthOutcome::Accepted(ClientIdentity {
+                client_id: format!("api-key:{index}"),
+            }),
+            None => AuthOutcome::NotApplicable,
+        }
+    }
+}
+
+/// Accepts short-lived tokens minted by a trusted party sharing `secret`, e.g. a game server.
+///
+/// Tokens look like `<client_id>.<expires_at>.<signature>`, where `expires_at` is a unix
+/// timestamp in seconds and `signature` is the unpadded base64url HMAC-SHA256 of
+/// `<client_id>.<expires_at>`. The identity is `token:<client_id>`, so a token can never
+/// pass for a client of another authenticator.
+pub struct HmacTokenAuthenticator {
+    secret: Vec<u8>,
+    max_lifetime: Duration,
+}
+
+impl HmacTokenAuthenticator {
+    pub fn new(secret: impl Into<Vec<u8>>, max_lifetime: Duration) -> Self {
+        Self {
+            secret: secret.into(),
+            max_lifetime,
+        }
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts any key size");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+impl Authenticator for HmacTokenAuthenticator {
+    fn authenticate(&self, credential: &str) -> AuthOutcome {
+        let Some((payload, signature)) = credential.rsplit_once('.') else {
+            return AuthOutcome::NotApplicable;
+        };
+        let Some((client_id, expires_at)) = payload.rsplit_once('.') else {
+            return AuthOutcome::NotApplicable;
+        };
+        let (Ok(expires_at), Ok(signature)) =
+            (expires_at.parse::<u64>(), BASE64_URL.decode(signature))
+        else {
+            return AuthOutcome::NotApplicable;
+        };
+
+        if self.mac(payload).verify_slice(&signature).is_err() {
+            return AuthOutcome::Rejected("Invalid token signature".to_string());
+        }
+        if client_id.is_empty() {
+            return AuthOutcome::Rejected("Token has no client id".to_string());
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if expires_at <= now {
+            return AuthOutcome::Rejected("Token expired".to_string());
+        }
+        if expires_at - now > self.max_lifetime.as_secs() {
+            return AuthOutcome::Rejected("Token lifetime too long".to_string());
+        }
+
+        AuthOutcome::Accepted(ClientIdentity {
+            client_id: format!("token:{client_id}"),
+        })
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Set of authenticators a credential is checked against, the first to accept it wins.
+///
+/// With no authenticators configured every client is let in.
+#[derive(Clone, Default)]
+pub struct ClientAuth {
+    authenticators: Arc<Vec<Box<dyn Authenticator>>>,
+}
+
+impl ClientAuth {
+    pub fn new(authenticators: Vec<Box<dyn Authenticator>>) -> Self {
+        Self {
+            authenticators: Arc::new(authenticators),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.authenticators.is_empty()
+    }
+
+    pub fn authenticate(&self, credential: Option<&str>) -> Result<ClientIdentity, String> {
+        if !self.is_enabled() {
+            return Ok(ClientIdentity {
+                client_id: "anonymous".to_string(),
+            });
+        }
+
+        let credential = credential.ok_or("Missing credentials")?;
+        let mut reason = None;
+        for authenticator in self.authenticators.iter() {
+            match authenticator.authenticate(credential) {
+                AuthOutcome::Accepted(identity) => return Ok(identity),
+                AuthOutcome::Rejected(rejection) => {
+                    reason.get_or_insert(rejection);
+                }
+                AuthOutcome::NotApplicable => {}
+            }
+        }
+
+        Err(reason.unwrap_or_else(|| "Invalid credentials".to_string()))
+    }
+}
+
+#[derive(Debug)]
+pub struct Unauthorized {
+    pub reason: String,
+}
+
+impl warp::reject::Reject for Unauthorized {}
+
+#[derive(Debug)]
+pub struct OriginNotAllowed;
+
+impl warp::reject::Reject for OriginNotAllowed {}
+
+#[derive(Debug, Default, serde::Deserialize)]
+struct AuthQuery {
+    #[serde(default)]
+    auth: Option<String>,
+}
+
+/// Authenticates a request from its `Authorization: Bearer` header or, for clients that can't
+/// set headers on a WebSocket upgrade, its `auth` query parameter.
+pub fn authenticated(
+    auth: ClientAuth,
+) -> impl Filter<Extract = (ClientIdentity,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<AuthQuery>())
+        .and_then(move |header: Option<String>, query: AuthQuery| {
+            let auth = auth.clone();
+            async move {
+                let credential = header
+                    .as_deref()
+                    .and_then(|header| header.strip_prefix("Bearer "))
+                    .map(str::to_string)
+                    .or(query.auth);
+
+                auth.authenticate(credential.as_deref())
+                    .map_err(|reason| warp::reject::custom(Unauthorized { reason }))
+            }
+        })
+}
+
+/// Rejects requests whose `Origin` header is not in `allowed_origins`. An empty list allows
+/// every origin, requests without an `Origin` header (non-browser clients) always pass.
+pub fn allowed_origin(
+    allowed_origins: Arc<Vec<String>>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and_then(move |origin: Option<String>| {
+            let allowed_origins = allowed_origins.clone();
+            async move {
+                match origin {
+                    Some(origin)
+                        if !allowed_origins.is_empty() && !allowed_origins.contains(&origin) =>
+                    {
+                        Err(warp::reject::custom(OriginNotAllowed))
+                    }
+                    _ => Ok(()),
+                }
+            }
+        })
+        .untuple_one()
+}
+
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    let (status, reason) = if let Some(Unauthorized { reason }) = err.find() {
+        (StatusCode::UNAUTHORIZED, reason.clone())
+    } else if err.find::<OriginNotAllowed>().is_some() {
+        (StatusCode::FORBIDDEN, "Origin not allowed".to_string())
+    } else {
+        return Err(err);
+    };
+
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "success": false,
+            "message": reason,
+        })),
+        status,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "shared-secret";
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn token(client_id: &str, expires_at: u64) -> String {
+        let payload = format!("{client_id}.{expires_at}");
+        let signature = HmacTokenAuthenticator::new(SECRET, HOUR)
+            .mac(&payload)
+            .finalize()
+            .into_bytes();
+        format!("{payload}.{}", BASE64_URL.encode(signature))
+    }
+
+    fn rejection(outcome: AuthOutcome) -> Option<String> {
+        match outcome {
+            AuthOutcome::Rejected(reason) => Some(reason),
+            _ => None,
+        }
+    }
+
+    fn client_id(outcome: AuthOutcome) -> Option<String> {
+        match outcome {
+            AuthOutcome::Accepted(identity) => Some(identity.client_id),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_token() {
+        let auth = HmacTokenAuthenticator::new(SECRET, HOUR);
+        let outcome = auth.authenticate(&token("player-1", now() + 60));
+        assert_eq!(client_id(outcome).as_deref(), Some("token:player-1"));
+    }
+
+    #[test]
+    fn client_ids_may_contain_dots() {
+        let auth = HmacTokenAuthenticator::new(SECRET, HOUR);
+        let outcome = auth.authenticate(&token("game.eu.player-1", now() + 60));
+        assert_eq!(
+            client_id(outcome).as_deref(),
+            Some("token:game.eu.player-1")
+        );
+    }
+
+    #[test]
+    fn rejects_a_tampered_token() {
+        let auth = HmacTokenAuthenticator::new(SECRET, HOUR);
+        let expires_at = now() + 60;
+        let valid = token("player-1", expires_at);
+        let signature = valid.rsplit_once('.').unwrap().1;
+
+        let other_client = format!("player-2.{expires_at}.{signature}");
+        assert_eq!(
+            rejection(auth.authenticate(&other_client)).as_deref(),
+            Some("Invalid token signature")
+        );
+
+        let other_secret = HmacTokenAuthenticator::new("other-secret", HOUR);
+        assert_eq!(
+            rejection(other_secret.authenticate(&token("player-1", expires_at))).as_deref(),
+            Some("Invalid token signature")
+        );
+    }
+
+    #[test]
+    fn rejects_an_expired_token() {
+        let auth = HmacTokenAuthenticator::new(SECRET, HOUR);
+        assert_eq!(
+            rejection(auth.authenticate(&token("player-1", now() - 1))).as_deref(),
+            Some("Token expired")
+        );
+    }
+
+    #[test]
+    fn rejects_a_token_that_lives_too_long() {
+        let auth = HmacTokenAuthenticator::new(SECRET, HOUR);
+        let expires_at = now() + HOUR.as_secs() + 60;
+        assert_eq!(
+            rejection(auth.authenticate(&token("player-1", expires_at))).as_deref(),
+            Some("Token lifetime too long")
+        );
+    }
+
+    #[test]
+    fn leaves_malformed_tokens_to_other_authenticators() {
+        let auth = HmacTokenAuthenticator::new(SECRET, HOUR);
+        let expires_at = now() + 60;
+        let valid = token("player-1", expires_at);
+        let signature = valid.rsplit_once('.').unwrap().1;
+
+        for credential in [
+            "api-key",
+            "player-1.signature",
+            &format!("player-1.soon.{signature}"),
+            &format!("player-1.{expires_at}.not base64!"),
+        ] {
+            assert!(
+                matches!(auth.authenticate(credential), AuthOutcome::NotApplicable),
+                "{credential}"
+            );
+        }
+    }
+
+    #[test]
+    fn api_keys_identify_the_key_used() {
+        let auth = ApiKeyAuthenticator::new(vec!["key-a".to_string(), "key-b".to_string()]);
+        assert_eq!(
+            client_id(auth.authenticate("key-b")).as_deref(),
+            Some("api-key:1")
+        );
+        assert!(matches!(
+            auth.authenticate("key-c"),
+            AuthOutcome::NotApplicable
+        ));
+        assert!(matches!(
+            auth.authenticate("key"),
+            AuthOutcome::NotApplicable
+        ));
+    }
+
+    #[test]
+    fn reports_why_a_token_was_rejected() {
+        let auth = ClientAuth::new(vec![
+            Box::new(ApiKeyAuthenticator::new(vec!["key-a".to_string()])),
+            Box::new(HmacTokenAuthenticator::new(SECRET, HOUR)),
+        ]);
+
+        assert_eq!(
+            auth.authenticate(Some("key-a")).unwrap().client_id,
+            "api-key:0"
+        );
+        assert_eq!(
+            auth.authenticate(Some(&token("player-1", now() - 1)))
+                .unwrap_err(),
+            "Token expired"
+        );
+        assert_eq!(
+            auth.authenticate(Some("key-b")).unwrap_err(),
+            "Invalid credentials"
+        );
+        assert_eq!(auth.authenticate(None).unwrap_err(), "Missing credentials");
+    }
+
+    #[tokio::test]
+    async fn only_listed_origins_are_allowed() {
+        let filter = allowed_origin(Arc::new(vec!["https://game.example".to_string()]));
+
+        let allowed = warp::test::request().header("origin", "https://game.example");
+        assert!(allowed.filter(&filter).await.is_ok());
+        // Non-browser clients don't send an origin
+        assert!(warp::test::request().filter(&filter).await.is_ok());
+
+        for origin in ["https://evil.example", "https://game.example.evil", "null"] {
+            let request = warp::test::request().header("origin", origin);
+            let rejection = request.filter(&filter).await.unwrap_err();
+            assert!(rejection.find::<OriginNotAllowed>().is_some(), "{origin}");
+        }
+    }
+
+    #[tokio::test]
+    async fn every_origin_is_allowed_without_a_list() {
+        let filter = allowed_origin(Arc::new(Vec::new()));
+        let request = warp::test::request().header("origin", "https://any.example");
+        assert!(request.filter(&filter).await.is_ok());
+    }
+
+    #[test]
+    fn tokens_cant_pass_for_other_identities() {
+        let auth = ClientAuth::new(vec![
+            Box::new(ApiKeyAuthenticator::new(vec!["key-a".to_string()])),
+            Box::new(HmacTokenAuthenticator::new(SECRET, HOUR)),
+        ]);
+        let api_key_client = auth.authenticate(Some("key-a")).unwrap().client_id;
+        let anonymous = ClientAuth::default().authenticate(None).unwrap().client_id;
+
+        for client_id in [&api_key_client, &anonymous] {
+            let identity = auth
+                .authenticate(Some(&token(client_id, now() + 60)))
+                .unwrap();
+            assert_ne!(&identity.client_id, client_id);
+        }
+    }
+
+    #[test]
+    fn rejects_a_token_without_a_client_id() {
+        let auth = HmacTokenAuthenticator::new(SECRET, HOUR);
+        assert_eq!(
+            rejection(auth.authenticate(&token("", now() + 60))).as_deref(),
+            Some("Token has no client id")
+        );
+    }
+}
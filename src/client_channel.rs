@@ -0,0 +1,621 @@
+use crate::ws_sink::AudioFraming;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::Notify;
+use warp::ws::Message;
+
+/// Control messages a connection may have queued before it is considered stuck and closed.
+const CONTROL_QUEUE_LEN: usize = 1024;
+
+/// What to do with new audio when a connection's audio queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum AudioOverflowPolicy {
+    /// Drop the oldest queued frame to make room, the client hears a skip.
+    #[default]
+    DropOldest,
+    /// Drop the frame and pause playback until the client resumes it.
+    Pause,
+    /// Close the connection, the client can reconnect and resume its session.
+    Disconnect,
+}
+
+/// What happened to an audio frame handed to [`OutgoingSender::send_audio`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioSendOutcome {
+    Queued,
+    /// Queued after dropping the oldest queued frame.
+    DroppedOldest,
+    /// Not queued, playback should be paused.
+    Pause,
+    /// Not queued because the connection is closed or being closed.
+    Closed,
+}
+
+/// The outgoing queue was closed, by the socket going away or by an overflow.
+#[derive(Debug)]
+pub struct QueueClosed;
+
+impl fmt::Display for QueueClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("connection closed")
+    }
+}
+
+impl std::error::Error for QueueClosed {}
+
+struct QueuedAudio {
+    message: Message,
+    /// Stream markers such as `audio_format` are never dropped.
+    marker: bool,
+}
+
+struct Queues {
+    control: VecDeque<Message>,
+    /// Audio frames and the stream markers between them, in stream order.
+    audio: VecDeque<QueuedAudio>,
+    /// Entries of `audio` that are frames, the ones the capacity applies to.
+    audio_frames: usize,
+}
+
+struct OutgoingQueue {
+    queues: Mutex<Queues>,
+    audio_capacity: usize,
+    overflow_policy: AudioOverflowPolicy,
+    closed: AtomicBool,
+    notify: Notify,
+    /// Notified when the receiver took the last queued message, or the queue closed.
+    drained: Notify,
+}
+
+/// Sending half of a connection's outgoing messages.
+///
+/// Control messages (responses and events) and audio are queued separately and both are
+/// bounded, so a slow client can't make the server buffer without limit. Control messages
+/// are always written first. Messages that describe the audio stream go in the audio queue
+/// as stream markers, so they can't overtake the frames queued before them.
+#[derive(Clone)]
+pub struct OutgoingSender {
+    queue: Arc<OutgoingQueue>,
+}
+
+impl OutgoingSender {
+    /// Queues a control message. A client that lets [`CONTROL_QUEUE_LEN`] of them pile up is
+    /// disconnected.
+    pub fn send(&self, message: Message) -> Result<(), QueueClosed> {
+        if self.is_closed() {
+            return Err(QueueClosed);
+        }
+
+        let mut queues = self.queue.queues.lock().unwrap();
+        if queues.control.len() >= CONTROL_QUEUE_LEN {
+            drop(queues);
+            self.close(1008, "Control queue overflow");
+            return Err(QueueClosed);
+        }
+        queues.control.push_back(message);
+        drop(queues);
+
+        self.queue.notify.notify_one();
+        Ok(())
+    }
+
+    /// Queues an audio frame, applying the connection's [`AudioOverflowPolicy`] if the audio
+    /// queue is full.
+    pub fn send_audio(&self, message: Message) -> AudioSendOutcome {
+        if self.is_closed() {
+            return AudioSendOutcome::Closed;
+        }
+
+        let mut queues = self.queue.queues.lock().unwrap();
+        let mut outcome = AudioSendOutcome::Queued;
+        if queues.audio_frames >= self.queue.audio_capacity {
+            match self.queue.overflow_policy {
+                AudioOverflowPolicy::DropOldest => {
+                    if let Some(oldest) = queues.audio.iter().position(|queued| !queued.marker) {
+                        queues.audio.remove(oldest);
+                        queues.audio_frames -= 1;
+                    }
+                    outcome = AudioSendOutcome::DroppedOldest;
+                }
+                AudioOverflowPolicy::Pause => return AudioSendOutcome::Pause,
+                AudioOverflowPolicy::Disconnect => {
+                    drop(queues);
+                    self.close(1008, "Audio queue overflow");
+                    return AudioSendOutcome::Closed;
+                }
+            }
+        }
+        queues.audio.push_back(QueuedAudio {
+            message,
+            marker: false,
+        });
+        queues.audio_frames += 1;
+        drop(queues);
+
+        self.queue.notify.notify_one();
+        outcome
+    }
+
+    /// Queues a stream marker behind the audio frames queued so far. Markers are never dropped
+    /// and don't count towards the audio capacity, a client that lets [`CONTROL_QUEUE_LEN`]
+    /// of them pile up is disconnected.
+    pub fn send_marker(&self, message: Message) -> Result<(), QueueClosed> {
+        if self.is_closed() {
+            return Err(QueueClosed);
+        }
+
+        let mut queues = self.queue.queues.lock().unwrap();
+        if queues.audio.len() - queues.audio_frames >= CONTROL_QUEUE_LEN {
+            drop(queues);
+            self.close(1008, "Audio queue overflow");
+            return Err(QueueClosed);
+        }
+        queues.audio.push_back(QueuedAudio {
+            message,
+            marker: true,
+        });
+        drop(queues);
+
+        self.queue.notify.notify_one();
+        Ok(())
+    }
+
+    /// Writes `reason` as the last message and closes the connection, queued audio is dropped.
+    pub fn close(&self, code: u16, reason: &str) {
+        let mut queues = self.queue.queues.lock().unwrap();
+        if self.queue.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        queues.audio.clear();
+        queues.audio_frames = 0;
+        queues
+            .control
+            .push_back(Message::close_with(code, reason.to_string()));
+        drop(queues);
+
+        self.queue.notify.notify_one();
+        self.queue.drained.notify_waiters();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.queue.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn overflow_policy(&self) -> AudioOverflowPolicy {
+        self.queue.overflow_policy
+    }
+
+    /// Queued control and audio messages.
+    pub fn pending(&self) -> (usize, usize) {
+        let queues = self.queue.queues.lock().unwrap();
+        (queues.control.len(), queues.audio.len())
+    }
+
+    pub fn same_queue(&self, other: &OutgoingSender) -> bool {
+        Arc::ptr_eq(&self.queue, &other.queue)
+    }
+
+    /// Waits until the receiver has taken every queued message, or the queue is closed.
+    pub async fn flushed(&self) {
+        loop {
+            let drained = self.queue.drained.notified();
+            tokio::pin!(drained);
+            // Registered before checking, so a drain in between is not missed
+            drained.as_mut().enable();
+            if self.is_closed() || self.pending() == (0, 0) {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+/// Receiving half of a connection's outgoing messages, meant to be written to its socket.
+pub struct OutgoingReceiver {
+    queue: Arc<OutgoingQueue>,
+}
+
+impl OutgoingReceiver {
+    /// Next message to write, control messages first. Returns `None` once the queue is closed
+    /// and its remaining control messages were taken.
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            {
+                let mut queues = self.queue.queues.lock().unwrap();
+                let message = match queues.control.pop_front() {
+                    Some(message) => Some(message),
+                    None if self.queue.closed.load(Ordering::Relaxed) => return None,
+                    None => queues.audio.pop_front().map(|queued| {
+                        if !queued.marker {
+                            queues.audio_frames -= 1;
+                        }
+                        queued.message
+                    }),
+                };
+                if let Some(message) = message {
+                    if queues.control.is_empty() && queues.audio.is_empty() {
+                        self.queue.drained.notify_waiters();
+                    }
+                    return Some(message);
+                }
+            }
+            // A notification sent since the last check is kept, so nothing is missed
+            self.queue.notify.notified().await;
+        }
+    }
+}
+
+impl Drop for OutgoingReceiver {
+    fn drop(&mut self) {
+        self.queue.closed.store(true, Ordering::Relaxed);
+        self.queue.drained.notify_waiters();
+    }
+}
+
+/// Creates the outgoing queues of a connection.
+pub fn outgoing_queue(
+    audio_capacity: usize,
+    overflow_policy: AudioOverflowPolicy,
+) -> (OutgoingSender, OutgoingReceiver) {
+    let queue = Arc::new(OutgoingQueue {
+        queues: Mutex::new(Queues {
+            control: VecDeque::new(),
+            audio: VecDeque::with_capacity(audio_capacity),
+            audio_frames: 0,
+        }),
+        audio_capacity,
+        overflow_policy,
+        closed: AtomicBool::new(false),
+        notify: Notify::new(),
+        drained: Notify::new(),
+    });
+
+    (
+        OutgoingSender {
+            queue: queue.clone(),
+        },
+        OutgoingReceiver { queue },
+    )
+}
+
+#[derive(Default)]
+struct ChannelState {
+    sender: Option<OutgoingSender>,
+    framing: AudioFraming,
+    /// `audio_format` of every running stream by device id, for sockets attached later.
+    audio_formats: HashMap<String, serde_json::Value>,
+}
+
+impl ChannelState {
+    fn send_marker(&self, value: &serde_json::Value) -> bool {
+        let sender = self.sender.as_ref().filter(|sender| !sender.is_closed());
+        let (Some(sender), Ok(msg)) = (sender, serde_json::to_string(value)) else {
+            return false;
+        };
+        sender.send_marker(Message::text(msg)).is_ok()
+    }
+}
+
+/// Route from a device to whichever WebSocket currently owns it.
+///
+/// Devices and their sinks keep a clone of the channel for their whole lifetime, so a client
+/// reconnecting only has to [`attach`](Self::attach) its new socket for events and audio to
+/// follow it. Messages sent while no socket is attached are dropped.
+#[derive(Clone, Default)]
+pub struct ClientChannel {
+    state: Arc<RwLock<ChannelState>>,
+}
+
+impl ClientChannel {
+    /// Routes messages to `sender` from now on. Streams that are running are announced to it
+    /// with their `audio_format`, updated to `framing`, ahead of their next frame.
+    pub fn attach(&self, sender: OutgoingSender, framing: AudioFraming) {
+        let mut state = self.state.write().unwrap();
+        for format in state.audio_formats.values_mut() {
+            if let Some(data) = format.get_mut("data").and_then(|data| data.as_object_mut()) {
+                data.insert("framing".into(), serde_json::json!(framing));
+            }
+            if let Ok(msg) = serde_json::to_string(format) {
+                let _ = sender.send_marker(Message::text(msg));
+            }
+        }
+        state.sender = Some(sender);
+        state.framing = framing;
+    }
+
+    pub fn detach(&self) {
+        self.state.write().unwrap().sender = None;
+    }
+
+    fn sender(&self) -> Option<OutgoingSender> {
+        self.state
+            .read()
+            .unwrap()
+            .sender
+            .clone()
+            .filter(|sender| !sender.is_closed())
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.sender().is_some()
+    }
+
+    /// Waits until the attached socket has taken every queued message, see
+    /// [`OutgoingSender::flushed`]. Returns right away while detached.
+    pub async fn flushed(&self) {
+        if let Some(sender) = self.sender() {
+            sender.flushed().await;
+        }
+    }
+
+    pub fn framing(&self) -> AudioFraming {
+        self.state.read().unwrap().framing
+    }
+
+    /// Returns whether the message was handed to a connected socket.
+    pub fn send(&self, message: Message) -> bool {
+        match self.sender() {
+            Some(sender) => sender.send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn send_json(&self, value: &serde_json::Value) -> bool {
+        match serde_json::to_string(value) {
+            Ok(msg) => self.send(Message::text(msg)),
+            Err(_) => false,
+        }
+    }
+
+    /// Queues a stream marker for the attached socket, see [`OutgoingSender::send_marker`].
+    /// Returns whether it was queued.
+    pub fn send_marker(&self, value: &serde_json::Value) -> bool {
+        self.state.read().unwrap().send_marker(value)
+    }
+
+    /// Queues the `audio_format` marker of a stream starting on `device_id` and keeps it to
+    /// announce the stream to sockets attached before it stops. Returns whether it was queued.
+    pub fn start_stream(&self, device_id: &str, format: &serde_json::Value) -> bool {
+        // Held while queueing, so an attach can't slip in between and announce it twice
+        let mut state = self.state.write().unwrap();
+        state
+            .audio_formats
+            .insert(device_id.to_string(), format.clone());
+        state.send_marker(format)
+    }
+
+    /// Queues the `audio_stream_stopped` marker of the stream of `device_id`. Returns whether
+    /// it was queued.
+    pub fn stop_stream(&self, device_id: &str, stopped: &serde_json::Value) -> bool {
+        let mut state = self.state.write().unwrap();
+        state.audio_formats.remove(device_id);
+        state.send_marker(stopped)
+    }
+
+    /// Queues an audio frame for the attached socket, see [`OutgoingSender::send_audio`].
+    pub fn send_audio(&self, message: Message) -> AudioSendOutcome {
+        match self.sender() {
+            Some(sender) => sender.send_audio(message),
+            None => AudioSendOutcome::Closed,
+        }
+    }
+
+    pub fn overflow_policy(&self) -> AudioOverflowPolicy {
+        self.sender()
+            .map(|sender| sender.overflow_policy())
+            .unwrap_or_default()
+    }
+
+    pub fn close(&self, code: u16, reason: &str) {
+        if let Some(sender) = self.sender() {
+            sender.close(code, reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(message: Option<Message>) -> String {
+        message.unwrap().to_str().unwrap().to_string()
+    }
+
+    async fn drain(receiver: &mut OutgoingReceiver) -> Vec<String> {
+        let mut messages = Vec::new();
+        while let Some(message) =
+            tokio::time::timeout(std::time::Duration::from_millis(10), receiver.recv())
+                .await
+                .ok()
+                .flatten()
+        {
+            messages.push(message.to_str().unwrap_or("<close>").to_string());
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn control_messages_go_first() {
+        let (sender, mut receiver) = outgoing_queue(4, AudioOverflowPolicy::DropOldest);
+        sender.send_audio(Message::text("frame"));
+        sender.send(Message::text("event")).unwrap();
+
+        assert_eq!(text(receiver.recv().await), "event");
+        assert_eq!(text(receiver.recv().await), "frame");
+    }
+
+    #[tokio::test]
+    async fn drop_oldest_makes_room_for_new_frames() {
+        let (sender, mut receiver) = outgoing_queue(2, AudioOverflowPolicy::DropOldest);
+        assert_eq!(
+            sender.send_audio(Message::text("1")),
+            AudioSendOutcome::Queued
+        );
+        assert_eq!(
+            sender.send_audio(Message::text("2")),
+            AudioSendOutcome::Queued
+        );
+        assert_eq!(
+            sender.send_audio(Message::text("3")),
+            AudioSendOutcome::DroppedOldest
+        );
+
+        assert_eq!(drain(&mut receiver).await, ["2", "3"]);
+    }
+
+    #[tokio::test]
+    async fn pause_keeps_the_queued_frames() {
+        let (sender, mut receiver) = outgoing_queue(2, AudioOverflowPolicy::Pause);
+        sender.send_audio(Message::text("1"));
+        sender.send_audio(Message::text("2"));
+        assert_eq!(
+            sender.send_audio(Message::text("3")),
+            AudioSendOutcome::Pause
+        );
+        assert!(!sender.is_closed());
+
+        assert_eq!(drain(&mut receiver).await, ["1", "2"]);
+        assert_eq!(
+            sender.send_audio(Message::text("4")),
+            AudioSendOutcome::Queued
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnect_closes_the_connection() {
+        let (sender, mut receiver) = outgoing_queue(2, AudioOverflowPolicy::Disconnect);
+        sender.send_audio(Message::text("1"));
+        sender.send_audio(Message::text("2"));
+        assert_eq!(
+            sender.send_audio(Message::text("3")),
+            AudioSendOutcome::Closed
+        );
+        assert!(sender.is_closed());
+
+        // Queued audio is dropped, only the close frame is written
+        assert!(receiver.recv().await.unwrap().is_close());
+        assert!(receiver.recv().await.is_none());
+        assert_eq!(
+            sender.send_audio(Message::text("4")),
+            AudioSendOutcome::Closed
+        );
+    }
+
+    #[tokio::test]
+    async fn markers_keep_their_place_and_are_never_dropped() {
+        let (sender, mut receiver) = outgoing_queue(2, AudioOverflowPolicy::DropOldest);
+        sender.send_audio(Message::text("1"));
+        sender.send_marker(Message::text("stopped")).unwrap();
+        sender.send_marker(Message::text("format")).unwrap();
+        sender.send_audio(Message::text("2"));
+        sender.send_audio(Message::text("3"));
+        // Events still go first, but not ahead of the markers' place in the audio
+        sender.send(Message::text("event")).unwrap();
+
+        assert_eq!(
+            drain(&mut receiver).await,
+            ["event", "stopped", "format", "2", "3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn markers_do_not_count_towards_the_audio_capacity() {
+        let (sender, _receiver) = outgoing_queue(1, AudioOverflowPolicy::Pause);
+        sender.send_marker(Message::text("format")).unwrap();
+        assert_eq!(
+            sender.send_audio(Message::text("1")),
+            AudioSendOutcome::Queued
+        );
+        assert_eq!(
+            sender.send_audio(Message::text("2")),
+            AudioSendOutcome::Pause
+        );
+        assert_eq!(sender.pending(), (0, 2));
+    }
+
+    #[tokio::test]
+    async fn control_queue_overflow_closes_the_connection() {
+        let (sender, mut receiver) = outgoing_queue(1, AudioOverflowPolicy::DropOldest);
+        for _ in 0..CONTROL_QUEUE_LEN {
+            sender.send(Message::text("event")).unwrap();
+        }
+        assert!(sender.send(Message::text("event")).is_err());
+        assert!(sender.is_closed());
+
+        let messages = drain(&mut receiver).await;
+        assert_eq!(messages.len(), CONTROL_QUEUE_LEN + 1);
+        assert_eq!(messages.last().unwrap(), "<close>");
+    }
+
+    #[tokio::test]
+    async fn running_streams_are_announced_to_a_resumed_socket() {
+        let channel = ClientChannel::default();
+        let (first, _first_receiver) = outgoing_queue(4, AudioOverflowPolicy::DropOldest);
+        channel.attach(first, AudioFraming::Json);
+        let format = |device_id: &str| {
+            serde_json::json!({
+                "type": "audio_format",
+                "device_id": device_id,
+                "data": { "framing": AudioFraming::Json },
+            })
+        };
+        assert!(channel.start_stream("a", &format("a")));
+        assert!(channel.start_stream("b", &format("b")));
+        assert!(channel.stop_stream("b", &serde_json::json!({ "type": "audio_stream_stopped" })));
+
+        channel.detach();
+        let (second, mut second_receiver) = outgoing_queue(4, AudioOverflowPolicy::DropOldest);
+        channel.attach(second, AudioFraming::Binary);
+
+        let messages = drain(&mut second_receiver).await;
+        assert_eq!(messages.len(), 1);
+        let announced: serde_json::Value = serde_json::from_str(&messages[0]).unwrap();
+        assert_eq!(announced["device_id"], "a");
+        assert_eq!(
+            announced["data"]["framing"],
+            serde_json::json!(AudioFraming::Binary)
+        );
+    }
+
+    #[tokio::test]
+    async fn flushed_waits_for_the_receiver() {
+        let (sender, mut receiver) = outgoing_queue(4, AudioOverflowPolicy::DropOldest);
+        sender.send(Message::text("event")).unwrap();
+        sender.send_audio(Message::text("frame"));
+
+        let flushed = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.flushed().await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        assert!(!flushed.is_finished());
+
+        receiver.recv().await;
+        receiver.recv().await;
+        tokio::time::timeout(std::time::Duration::from_secs(1), flushed)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn flushed_returns_once_the_socket_is_gone() {
+        let (sender, receiver) = outgoing_queue(4, AudioOverflowPolicy::DropOldest);
+        sender.send(Message::text("event")).unwrap();
+
+        let flushed = tokio::spawn({
+            let sender = sender.clone();
+            async move { sender.flushed().await }
+        });
+        drop(receiver);
+        tokio::time::timeout(std::time::Duration::from_secs(1), flushed)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
@@ -1,10 +1,20 @@
 use crate::commands::{Command, CommandResponse};
-use crate::spotify::SpotifyClient;
+use crate::metrics::metrics;
+use crate::spotify::{MetadataSource, SpotifyClient};
+use std::future::Future;
 
 pub trait CommandHandler {
     fn handle(client: &SpotifyClient, command: &Command) -> CommandResponse;
 }
 
+/// Handler for commands that have to wait on the Spotify session, e.g. metadata lookups.
+pub trait AsyncCommandHandler {
+    fn handle(
+        source: &MetadataSource,
+        command: &Command,
+    ) -> impl Future<Output = CommandResponse> + Send;
+}
+
 macro_rules! impl_simple_handler {
     ($name:ident, $method:ident, $success_msg:expr) => {
         pub struct $name;
@@ -30,6 +40,7 @@ impl_simple_handler!(VolumeUpCommandHandler, volume_up, "Volume increased");
 impl_simple_handler!(VolumeDownCommandHandler, volume_down, "Volume decreased");
 impl_simple_handler!(ShutdownCommandHandler, shutdown, "Device shutdown");
 impl_simple_handler!(ActivateCommandHandler, activate, "Device activated");
+impl_simple_handler!(ClearQueueCommandHandler, clear_queue, "Queue cleared");
 
 // Handlers for commands with parameters
 pub struct ShuffleCommandHandler;
@@ -128,6 +139,99 @@ impl CommandHandler for SetVolumeCommandHandler {
     }
 }
 
+pub struct LoadCommandHandler;
+impl CommandHandler for LoadCommandHandler {
+    fn handle(client: &SpotifyClient, command: &Command) -> CommandResponse {
+        if let Command::Load(params) = command {
+            match client.load(params) {
+                Ok(()) => CommandResponse::success(format!("Loading {}", params.context_uri), None),
+                Err(e) => CommandResponse::error(format!("Failed to load: {e}")),
+            }
+        } else {
+            CommandResponse::error("Invalid load command")
+        }
+    }
+}
+
+pub struct AddToQueueCommandHandler;
+impl CommandHandler for AddToQueueCommandHandler {
+    fn handle(client: &SpotifyClient, command: &Command) -> CommandResponse {
+        if let Command::AddToQueue { uri } = command {
+            match client.add_to_queue(uri) {
+                Ok(()) => CommandResponse::success(format!("Queued {uri}"), None),
+                Err(e) => CommandResponse::error(format!("Failed to add to queue: {e}")),
+            }
+        } else {
+            CommandResponse::error("Invalid add to queue command")
+        }
+    }
+}
+
+pub struct GetQueueCommandHandler;
+impl CommandHandler for GetQueueCommandHandler {
+    fn handle(client: &SpotifyClient, _command: &Command) -> CommandResponse {
+        CommandResponse::success(
+            "Queue retrieved",
+            Some(serde_json::json!({ "queue": client.queue() })),
+        )
+    }
+}
+
+pub struct GetStateCommandHandler;
+impl CommandHandler for GetStateCommandHandler {
+    fn handle(client: &SpotifyClient, _command: &Command) -> CommandResponse {
+        match serde_json::to_value(client.state()) {
+            Ok(state) => CommandResponse::success("State retrieved", Some(state)),
+            Err(e) => CommandResponse::error(format!("Failed to serialize state: {e}")),
+        }
+    }
+}
+
+pub struct GetTrackMetadataCommandHandler;
+impl AsyncCommandHandler for GetTrackMetadataCommandHandler {
+    async fn handle(source: &MetadataSource, command: &Command) -> CommandResponse {
+        if let Command::GetTrackMetadata { uri } = command {
+            let metadata = match source.track_metadata(uri.as_deref()).await {
+                Ok(metadata) => metadata,
+                Err(e) => return CommandResponse::error(format!("Failed to get metadata: {e}")),
+            };
+            match serde_json::to_value(metadata) {
+                Ok(metadata) => {
+                    CommandResponse::success("Track metadata retrieved", Some(metadata))
+                }
+                Err(e) => CommandResponse::error(format!("Failed to serialize metadata: {e}")),
+            }
+        } else {
+            CommandResponse::error("Invalid get track metadata command")
+        }
+    }
+}
+
+pub struct GetCoverArtCommandHandler;
+impl AsyncCommandHandler for GetCoverArtCommandHandler {
+    async fn handle(source: &MetadataSource, command: &Command) -> CommandResponse {
+        if let Command::GetCoverArt { uri } = command {
+            let (metadata, cover_art) = match source.cover_art(uri.as_deref()).await {
+                Ok(result) => result,
+                Err(e) => return CommandResponse::error(format!("Failed to get cover art: {e}")),
+            };
+            match cover_art.to_json(&metadata.track_id) {
+                Ok(data) => CommandResponse::success("Cover art retrieved", Some(data)),
+                Err(e) => CommandResponse::error(format!("Failed to convert cover art: {e}")),
+            }
+        } else {
+            CommandResponse::error("Invalid get cover art command")
+        }
+    }
+}
+
+/// Outcome of handing a command to a device.
+enum Dispatched {
+    Done(CommandResponse),
+    /// Still has to be looked up, without the device.
+    Lookup(MetadataSource, Command),
+}
+
 #[derive(Clone)]
 pub struct CommandManager;
 impl CommandManager {
@@ -135,8 +239,29 @@ impl CommandManager {
         Self
     }
 
-    pub fn execute(&self, command: Command, client: &SpotifyClient) -> CommandResponse {
-        match command {
+    /// Runs `command` on `client`.
+    ///
+    /// Device commands run before this returns. Lookups only take what they need from the
+    /// device, so callers can release it before awaiting the response.
+    pub fn execute(
+        &self,
+        command: Command,
+        client: &SpotifyClient,
+    ) -> impl Future<Output = CommandResponse> + Send + 'static {
+        let command_type = command.command_type();
+        let dispatched = self.dispatch(command, client);
+        async move {
+            let response = match dispatched {
+                Dispatched::Done(response) => response,
+                Dispatched::Lookup(source, command) => Self::lookup(&source, &command).await,
+            };
+            metrics().command_executed(command_type, response.success);
+            response
+        }
+    }
+
+    fn dispatch(&self, command: Command, client: &SpotifyClient) -> Dispatched {
+        let response = match command {
             Command::Play => PlayCommandHandler::handle(client, &command),
             Command::PlayPause => PlayPauseCommandHandler::handle(client, &command),
             Command::Pause => PauseCommandHandler::handle(client, &command),
@@ -152,9 +277,28 @@ impl CommandManager {
             Command::SetPosition(_) => SetPositionCommandHandler::handle(client, &command),
             Command::SetVolume(_) => SetVolumeCommandHandler::handle(client, &command),
             Command::Activate => ActivateCommandHandler::handle(client, &command),
+            Command::Load(_) => LoadCommandHandler::handle(client, &command),
+            Command::AddToQueue { .. } => AddToQueueCommandHandler::handle(client, &command),
+            Command::GetQueue => GetQueueCommandHandler::handle(client, &command),
+            Command::ClearQueue => ClearQueueCommandHandler::handle(client, &command),
+            Command::GetState => GetStateCommandHandler::handle(client, &command),
+            Command::GetTrackMetadata { .. } | Command::GetCoverArt { .. } => {
+                return Dispatched::Lookup(client.metadata_source(), command)
+            }
             Command::CreateDevice { .. } => {
                 CommandResponse::error("CreateDevice command should be handled by the server")
             }
+            Command::RemoveDevice => {
+                CommandResponse::error("RemoveDevice command should be handled by the server")
+            }
+        };
+        Dispatched::Done(response)
+    }
+
+    async fn lookup(source: &MetadataSource, command: &Command) -> CommandResponse {
+        match command {
+            Command::GetCoverArt { .. } => GetCoverArtCommandHandler::handle(source, command).await,
+            _ => GetTrackMetadataCommandHandler::handle(source, command).await,
         }
     }
 }
@@ -1,7 +1,11 @@
+use crate::ws_sink::OutputSpec;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct CommandMessage {
+    /// Client supplied identifier echoed back in the matching [`CommandResponse`].
+    #[serde(default)]
+    pub request_id: Option<serde_json::Value>,
     #[serde(default)]
     pub device_id: Option<String>,
     pub command_type: String,
@@ -9,13 +13,48 @@ pub struct CommandMessage {
     pub params: serde_json::Value,
 }
 
+/// Track within the loaded context that playback should start from.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum StartTrack {
+    Index(u32),
+    Uri(String),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoadParams {
+    /// Playlist, album, artist or track URI, e.g. `spotify:playlist:37i9dQZF1DXcBWIGoYBM5M`.
+    pub context_uri: String,
+    pub start_track: Option<StartTrack>,
+    pub position_ms: u32,
+    pub start_playing: bool,
+    pub shuffle: bool,
+    pub repeat: bool,
+    pub repeat_track: bool,
+}
+
+/// Optional device behaviour chosen when the device is created.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeviceOptions {
+    /// Push a `cover_art` event with the album cover on every track change.
+    pub cover_art_events: bool,
+    /// How far audio is sent ahead of real time, for the client's jitter buffer.
+    pub audio_lead_ms: u32,
+    /// PCM format, rate, channel count and frame size the sink sends.
+    pub output: OutputSpec,
+}
+
+const DEFAULT_AUDIO_LEAD_MS: u32 = 500;
+const MAX_AUDIO_LEAD_MS: u32 = 5000;
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum Command {
     CreateDevice {
         token: String,
         device_name: Option<String>,
+        options: DeviceOptions,
     },
+    RemoveDevice,
     Play,
     PlayPause,
     Pause,
@@ -33,9 +72,52 @@ pub enum Command {
     SetPosition(u32),
     SetVolume(u16),
     Activate,
+    Load(LoadParams),
+    AddToQueue {
+        uri: String,
+    },
+    GetQueue,
+    ClearQueue,
+    GetState,
+    GetTrackMetadata {
+        uri: Option<String>,
+    },
+    GetCoverArt {
+        uri: Option<String>,
+    },
 }
 
 impl Command {
+    /// The `command_type` this command is sent as.
+    pub fn command_type(&self) -> &'static str {
+        match self {
+            Command::CreateDevice { .. } => "CreateDevice",
+            Command::RemoveDevice => "RemoveDevice",
+            Command::Play => "Play",
+            Command::PlayPause => "PlayPause",
+            Command::Pause => "Pause",
+            Command::Prev => "Prev",
+            Command::Next => "Next",
+            Command::VolumeUp => "VolumeUp",
+            Command::VolumeDown => "VolumeDown",
+            Command::Shutdown => "Shutdown",
+            Command::Shuffle(_) => "Shuffle",
+            Command::Repeat(_) => "Repeat",
+            Command::RepeatTrack(_) => "RepeatTrack",
+            Command::Disconnect { .. } => "Disconnect",
+            Command::SetPosition(_) => "SetPosition",
+            Command::SetVolume(_) => "SetVolume",
+            Command::Activate => "Activate",
+            Command::Load(_) => "Load",
+            Command::AddToQueue { .. } => "AddToQueue",
+            Command::GetQueue => "GetQueue",
+            Command::ClearQueue => "ClearQueue",
+            Command::GetState => "GetState",
+            Command::GetTrackMetadata { .. } => "GetTrackMetadata",
+            Command::GetCoverArt { .. } => "GetCoverArt",
+        }
+    }
+
     pub fn from_message(msg: CommandMessage) -> Result<(String, Command), String> {
         let command = match msg.command_type.as_str() {
             "CreateDevice" => {
@@ -52,10 +134,45 @@ impl Command {
                     .and_then(|v| v.as_str())
                     .map(String::from);
 
-                (String::new(), Command::CreateDevice { token, device_name })
+                let audio_lead_ms = match msg.params.get("audio_lead_ms") {
+                    Some(v) => v
+                        .as_u64()
+                        .filter(|&ms| ms <= u64::from(MAX_AUDIO_LEAD_MS))
+                        .ok_or(format!(
+                            "Invalid audio_lead_ms parameter, expected 0 to {MAX_AUDIO_LEAD_MS}"
+                        ))? as u32,
+                    None => DEFAULT_AUDIO_LEAD_MS,
+                };
+
+                let output = match msg.params.get("output") {
+                    Some(v) => OutputSpec::deserialize(v)
+                        .map_err(|e| format!("Invalid output parameter: {e}"))?,
+                    None => OutputSpec::default(),
+                };
+                output.validate()?;
+
+                let options = DeviceOptions {
+                    cover_art_events: msg
+                        .params
+                        .get("cover_art_events")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false),
+                    audio_lead_ms,
+                    output,
+                };
+
+                (
+                    String::new(),
+                    Command::CreateDevice {
+                        token,
+                        device_name,
+                        options,
+                    },
+                )
             }
             cmd_type => {
                 let command = match cmd_type {
+                    "RemoveDevice" => Command::RemoveDevice,
                     "Play" => Command::Play,
                     "PlayPause" => Command::PlayPause,
                     "Pause" => Command::Pause,
@@ -119,6 +236,90 @@ impl Command {
                         )
                     }
                     "Activate" => Command::Activate,
+                    "AddToQueue" => {
+                        let uri = msg
+                            .params
+                            .get("uri")
+                            .and_then(|v| v.as_str())
+                            .ok_or("Missing or invalid uri parameter")?
+                            .to_string();
+                        Command::AddToQueue { uri }
+                    }
+                    "GetQueue" => Command::GetQueue,
+                    "ClearQueue" => Command::ClearQueue,
+                    "GetState" => Command::GetState,
+                    "GetTrackMetadata" => {
+                        let uri = match msg.params.get("uri") {
+                            Some(v) => Some(v.as_str().ok_or("Invalid uri parameter")?.to_string()),
+                            None => None,
+                        };
+                        Command::GetTrackMetadata { uri }
+                    }
+                    "GetCoverArt" => {
+                        let uri = match msg.params.get("uri") {
+                            Some(v) => Some(v.as_str().ok_or("Invalid uri parameter")?.to_string()),
+                            None => None,
+                        };
+                        Command::GetCoverArt { uri }
+                    }
+                    "Load" => {
+                        let context_uri = msg
+                            .params
+                            .get("context_uri")
+                            .and_then(|v| v.as_str())
+                            .ok_or("Missing or invalid context_uri parameter")?
+                            .to_string();
+
+                        let start_index = match msg.params.get("start_index") {
+                            Some(v) => Some(
+                                v.as_u64()
+                                    .ok_or("Invalid start_index parameter")?
+                                    .try_into()
+                                    .map_err(|_| "Start index value out of range")?,
+                            ),
+                            None => None,
+                        };
+                        let start_uri = match msg.params.get("start_uri") {
+                            Some(v) => Some(v.as_str().ok_or("Invalid start_uri parameter")?),
+                            None => None,
+                        };
+                        let start_track = match (start_index, start_uri) {
+                            (Some(_), Some(_)) => {
+                                return Err(
+                                    "Only one of start_index and start_uri can be given".into()
+                                )
+                            }
+                            (Some(index), None) => Some(StartTrack::Index(index)),
+                            (None, Some(uri)) => Some(StartTrack::Uri(uri.to_string())),
+                            (None, None) => None,
+                        };
+
+                        let position_ms = match msg.params.get("position_ms") {
+                            Some(v) => v
+                                .as_u64()
+                                .ok_or("Invalid position_ms parameter")?
+                                .try_into()
+                                .map_err(|_| "Position value out of range")?,
+                            None => 0,
+                        };
+
+                        let flag = |name: &str, default: bool| match msg.params.get(name) {
+                            Some(v) => v
+                                .as_bool()
+                                .ok_or_else(|| format!("Invalid {name} parameter")),
+                            None => Ok(default),
+                        };
+
+                        Command::Load(LoadParams {
+                            context_uri,
+                            start_track,
+                            position_ms,
+                            start_playing: flag("start_playing", true)?,
+                            shuffle: flag("shuffle", false)?,
+                            repeat: flag("repeat", false)?,
+                            repeat_track: flag("repeat_track", false)?,
+                        })
+                    }
                     _ => return Err(format!("Unknown command type: {cmd_type}")),
                 };
 
@@ -135,7 +336,10 @@ impl Command {
 }
 
 #[derive(Debug, Serialize)]
+#[serde(tag = "type", rename = "response")]
 pub struct CommandResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<serde_json::Value>,
     pub success: bool,
     pub message: String,
     pub data: Option<serde_json::Value>,
@@ -144,6 +348,7 @@ pub struct CommandResponse {
 impl CommandResponse {
     pub fn success(message: impl ToString, data: Option<serde_json::Value>) -> Self {
         Self {
+            request_id: None,
             success: true,
             message: message.to_string(),
             data,
@@ -152,9 +357,231 @@ impl CommandResponse {
 
     pub fn error(message: impl ToString) -> Self {
         Self {
+            request_id: None,
             success: false,
             message: message.to_string(),
             data: None,
         }
     }
+
+    pub fn with_request_id(mut self, request_id: Option<serde_json::Value>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamKind {
+    String,
+    Boolean,
+    Integer,
+}
+
+impl ParamKind {
+    pub fn json_type(self) -> &'static str {
+        match self {
+            ParamKind::String => "string",
+            ParamKind::Boolean => "boolean",
+            ParamKind::Integer => "integer",
+        }
+    }
+}
+
+pub struct ParamSpec {
+    pub name: &'static str,
+    pub kind: ParamKind,
+    pub required: bool,
+    pub description: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpMethod {
+    Get,
+    Post,
+}
+
+/// Description of a device command for the HTTP API and its OpenAPI document.
+///
+/// Parameters must match what [`Command::from_message`] reads from `params`.
+pub struct CommandSpec {
+    pub command_type: &'static str,
+    /// Last path segment of `/devices/{device_id}/...`
+    pub path: &'static str,
+    pub method: HttpMethod,
+    pub description: &'static str,
+    pub params: &'static [ParamSpec],
+}
+
+macro_rules! param {
+    ($name:literal, $kind:ident, $required:literal, $description:literal) => {
+        ParamSpec {
+            name: $name,
+            kind: ParamKind::$kind,
+            required: $required,
+            description: $description,
+        }
+    };
+}
+
+macro_rules! command_spec {
+    ($command_type:literal, $method:ident $path:literal, $description:literal $(, $param:expr)* $(,)?) => {
+        CommandSpec {
+            command_type: $command_type,
+            path: $path,
+            method: HttpMethod::$method,
+            description: $description,
+            params: &[$($param),*],
+        }
+    };
+}
+
+/// Commands that run against an existing device through the [`CommandManager`](crate::command_manager::CommandManager).
+pub const DEVICE_COMMANDS: &[CommandSpec] = &[
+    command_spec!("Play", Post "play", "Resume playback"),
+    command_spec!("PlayPause", Post "play_pause", "Toggle between playing and paused"),
+    command_spec!("Pause", Post "pause", "Pause playback"),
+    command_spec!("Prev", Post "prev", "Skip to the previous track"),
+    command_spec!("Next", Post "next", "Skip to the next track"),
+    command_spec!("VolumeUp", Post "volume_up", "Increase the volume by one step"),
+    command_spec!("VolumeDown", Post "volume_down", "Decrease the volume by one step"),
+    command_spec!("Shutdown", Post "shutdown", "Stop the Spotify Connect device"),
+    command_spec!(
+        "Shuffle",
+        Post "shuffle",
+        "Enable or disable shuffle",
+        param!("state", Boolean, true, "Whether shuffle is enabled"),
+    ),
+    command_spec!(
+        "Repeat",
+        Post "repeat",
+        "Enable or disable repeating the context",
+        param!("state", Boolean, true, "Whether repeat is enabled"),
+    ),
+    command_spec!(
+        "RepeatTrack",
+        Post "repeat_track",
+        "Enable or disable repeating the current track",
+        param!("state", Boolean, true, "Whether track repeat is enabled"),
+    ),
+    command_spec!(
+        "Disconnect",
+        Post "disconnect",
+        "Disconnect the device from the Spotify app controlling it",
+        param!("pause", Boolean, false, "Pause playback before disconnecting"),
+    ),
+    command_spec!(
+        "SetPosition",
+        Post "set_position",
+        "Seek within the current track",
+        param!("position", Integer, true, "Position in milliseconds"),
+    ),
+    command_spec!(
+        "SetVolume",
+        Post "set_volume",
+        "Set the volume",
+        param!("volume", Integer, true, "Volume from 0 to 65535"),
+    ),
+    command_spec!("Activate", Post "activate", "Make the device the active Spotify Connect device"),
+    command_spec!(
+        "Load",
+        Post "load",
+        "Start playing a context",
+        param!("context_uri", String, true, "Playlist, album, artist or track URI"),
+        param!("start_index", Integer, false, "Index of the track to start from"),
+        param!("start_uri", String, false, "URI of the track to start from"),
+        param!("position_ms", Integer, false, "Position to start the first track at"),
+        param!("start_playing", Boolean, false, "Start playback right away, defaults to true"),
+        param!("shuffle", Boolean, false, "Enable shuffle"),
+        param!("repeat", Boolean, false, "Enable repeating the context"),
+        param!("repeat_track", Boolean, false, "Enable repeating the current track"),
+    ),
+    command_spec!(
+        "AddToQueue",
+        Post "add_to_queue",
+        "Queue a track to play after the current one",
+        param!("uri", String, true, "Track URI"),
+    ),
+    command_spec!("GetQueue", Get "queue", "List the upcoming tracks from the Connect state"),
+    command_spec!("ClearQueue", Post "clear_queue", "Remove all queued tracks"),
+    command_spec!("GetState", Get "state", "Get a snapshot of the playback state"),
+    command_spec!(
+        "GetTrackMetadata",
+        Get "track_metadata",
+        "Get metadata of the current track or of the given one",
+        param!("uri", String, false, "Track URI, defaults to the current track"),
+    ),
+    command_spec!(
+        "GetCoverArt",
+        Get "cover_art",
+        "Get the album cover as PNG and Minecraft map colors",
+        param!("uri", String, false, "Track URI, defaults to the current track"),
+    ),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn load(params: serde_json::Value) -> Result<LoadParams, String> {
+        let mut params = params;
+        params["context_uri"] = json!("spotify:album:2up3OPMp9Tb4dAKM2erWXQ");
+        let message = CommandMessage {
+            request_id: None,
+            device_id: Some("device-a".to_string()),
+            command_type: "Load".to_string(),
+            params,
+        };
+        match Command::from_message(message)? {
+            (_, Command::Load(params)) => Ok(params),
+            (_, command) => panic!("parsed as {command:?}"),
+        }
+    }
+
+    #[test]
+    fn load_defaults() {
+        let params = load(json!({})).unwrap();
+        assert_eq!(params.position_ms, 0);
+        assert!(params.start_playing);
+        assert!(!params.shuffle && !params.repeat && !params.repeat_track);
+    }
+
+    #[test]
+    fn load_reads_position_and_flags() {
+        let params = load(json!({
+            "position_ms": 30_000,
+            "start_playing": false,
+            "shuffle": true,
+        }))
+        .unwrap();
+        assert_eq!(params.position_ms, 30_000);
+        assert!(!params.start_playing);
+        assert!(params.shuffle);
+    }
+
+    #[test]
+    fn load_rejects_mistyped_position() {
+        for position_ms in [json!("30000"), json!(-5), json!(1.5)] {
+            assert_eq!(
+                load(json!({ "position_ms": position_ms })).unwrap_err(),
+                "Invalid position_ms parameter"
+            );
+        }
+        assert_eq!(
+            load(json!({ "position_ms": u64::from(u32::MAX) + 1 })).unwrap_err(),
+            "Position value out of range"
+        );
+    }
+
+    #[test]
+    fn load_rejects_mistyped_flags() {
+        for flag in ["start_playing", "shuffle", "repeat", "repeat_track"] {
+            let mut params = json!({});
+            params[flag] = json!("true");
+            assert_eq!(
+                load(params).unwrap_err(),
+                format!("Invalid {flag} parameter")
+            );
+        }
+    }
 }
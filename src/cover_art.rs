@@ -0,0 +1,248 @@
+use anyhow::Result;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, RgbaImage};
+use std::io::Cursor;
+
+/// Width and height of a Minecraft map in pixels.
+pub const MAP_SIZE: u32 = 128;
+
+/// Base colors of the Minecraft (Java Edition 1.17+) map palette, indexed by base color id.
+/// Id 0 is transparent.
+const MAP_BASE_COLORS: [[u8; 3]; 62] = [
+    [0, 0, 0],
+    [127, 178, 56],
+    [247, 233, 163],
+    [199, 199, 199],
+    [255, 0, 0],
+    [160, 160, 255],
+    [167, 167, 167],
+    [0, 124, 0],
+    [255, 255, 255],
+    [164, 168, 184],
+    [151, 109, 77],
+    [112, 112, 112],
+    [64, 64, 255],
+    [143, 119, 72],
+    [255, 252, 245],
+    [216, 127, 51],
+    [178, 76, 216],
+    [102, 153, 216],
+    [229, 229, 51],
+    [127, 204, 25],
+    [242, 127, 165],
+    [76, 76, 76],
+    [153, 153, 153],
+    [76, 127, 153],
+    [127, 63, 178],
+    [51, 76, 178],
+    [102, 76, 51],
+    [102, 127, 51],
+    [153, 51, 51],
+    [25, 25, 25],
+    [250, 238, 77],
+    [92, 219, 213],
+    [74, 128, 255],
+    [0, 217, 58],
+    [129, 86, 49],
+    [112, 2, 0],
+    [209, 177, 161],
+    [159, 82, 36],
+    [149, 87, 108],
+    [112, 108, 138],
+    [186, 133, 36],
+    [103, 117, 53],
+    [160, 77, 78],
+    [57, 41, 35],
+    [135, 107, 98],
+    [87, 92, 92],
+    [122, 73, 88],
+    [76, 62, 92],
+    [76, 50, 35],
+    [76, 82, 42],
+    [142, 60, 46],
+    [37, 22, 16],
+    [189, 48, 49],
+    [148, 63, 97],
+    [92, 25, 29],
+    [22, 126, 134],
+    [58, 142, 140],
+    [86, 44, 62],
+    [20, 180, 133],
+    [100, 100, 100],
+    [216, 175, 147],
+    [127, 167, 150],
+];
+
+/// Brightness multipliers (out of 255) for the four shades of every base color.
+/// The map color index is `base_id * 4 + shade`.
+const MAP_SHADES: [u32; 4] = [180, 220, 255, 135];
+
+/// Pixels less opaque than this are mapped to the transparent color.
+const ALPHA_THRESHOLD: u8 = 128;
+
+/// Returns the RGB value Minecraft renders for a map color index, `None` for transparent ones.
+pub fn map_color_rgb(index: u8) -> Option<[u8; 3]> {
+    let base = MAP_BASE_COLORS.get(usize::from(index / 4))?;
+    if index < 4 {
+        return None;
+    }
+
+    let shade = MAP_SHADES[usize::from(index % 4)];
+    Some(base.map(|channel| (u32::from(channel) * shade / 255) as u8))
+}
+
+/// Finds the closest map color using the same weighted RGB distance as Bukkit's `MapPalette`.
+fn nearest_map_color(rgb: [u8; 3]) -> u8 {
+    let mut best_index = 0;
+    let mut best_distance = f64::MAX;
+
+    for index in 4..(MAP_BASE_COLORS.len() * 4) as u8 {
+        let Some(candidate) = map_color_rgb(index) else {
+            continue;
+        };
+
+        let mean_red = (f64::from(rgb[0]) + f64::from(candidate[0])) / 2.0;
+        let red = f64::from(rgb[0]) - f64::from(candidate[0]);
+        let green = f64::from(rgb[1]) - f64::from(candidate[1]);
+        let blue = f64::from(rgb[2]) - f64::from(candidate[2]);
+        let distance = (2.0 + mean_red / 256.0) * red * red
+            + 4.0 * green * green
+            + (2.0 + (255.0 - mean_red) / 256.0) * blue * blue;
+
+        if distance < best_distance {
+            best_distance = distance;
+            best_index = index;
+        }
+    }
+
+    best_index
+}
+
+/// A decoded album cover.
+pub struct CoverArt {
+    image: RgbaImage,
+}
+
+impl CoverArt {
+    /// Decodes a cover in any of the supported formats (Spotify serves JPEG).
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let image = image::load_from_memory(bytes)
+            .map_err(|e| anyhow::anyhow!("Failed to decode cover image: {e}"))?;
+        Ok(Self {
+            image: image.to_rgba8(),
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.image.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.image.height()
+    }
+
+    pub fn to_png(&self) -> Result<Vec<u8>> {
+        let mut png = Vec::new();
+        self.image
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .map_err(|e| anyhow::anyhow!("Failed to encode cover as PNG: {e}"))?;
+        Ok(png)
+    }
+
+    /// Data of `cover_art` events and `GetCoverArt` responses. Both images are base64 encoded,
+    /// `map_colors` holds `MAP_SIZE * MAP_SIZE` palette indices row by row.
+    pub fn to_json(&self, track_id: &str) -> Result<serde_json::Value> {
+        Ok(serde_json::json!({
+            "track_id": track_id,
+            "width": self.width(),
+            "height": self.height(),
+            "png": BASE64.encode(self.to_png()?),
+            "map_size": MAP_SIZE,
+            "map_colors": BASE64.encode(self.to_map_colors()),
+        }))
+    }
+
+    /// Scales the cover to a single map and converts it to map color indices, row by row.
+    pub fn to_map_colors(&self) -> Vec<u8> {
+        let scaled = DynamicImage::ImageRgba8(self.image.clone())
+            .resize_exact(MAP_SIZE, MAP_SIZE, FilterType::Triangle)
+            .to_rgba8();
+
+        scaled
+            .pixels()
+            .map(|pixel| {
+                let [red, green, blue, alpha] = pixel.0;
+                if alpha < ALPHA_THRESHOLD {
+                    0
+                } else {
+                    nearest_map_color([red, green, blue])
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUADRANTS_JPG: &[u8] = include_bytes!("../tests/fixtures/cover_quadrants.jpg");
+    const TRANSPARENT_PNG: &[u8] = include_bytes!("../tests/fixtures/cover_transparent.png");
+
+    fn map_color_at(colors: &[u8], x: u32, y: u32) -> u8 {
+        colors[(y * MAP_SIZE + x) as usize]
+    }
+
+    #[test]
+    fn palette_colors_map_to_themselves() {
+        for index in 4..(MAP_BASE_COLORS.len() * 4) as u8 {
+            let rgb = map_color_rgb(index).unwrap();
+            assert_eq!(map_color_rgb(nearest_map_color(rgb)), Some(rgb));
+        }
+    }
+
+    #[test]
+    fn transparent_indices_have_no_color() {
+        for index in 0..4 {
+            assert_eq!(map_color_rgb(index), None);
+        }
+        assert_eq!(map_color_rgb((MAP_BASE_COLORS.len() * 4) as u8), None);
+    }
+
+    #[test]
+    fn jpeg_cover_is_scaled_to_one_map() {
+        let cover = CoverArt::decode(QUADRANTS_JPG).unwrap();
+        assert_eq!((cover.width(), cover.height()), (64, 64));
+
+        let colors = cover.to_map_colors();
+        assert_eq!(colors.len(), (MAP_SIZE * MAP_SIZE) as usize);
+
+        // Fixture quadrants: red, white / black, green. Pure black is closest to the
+        // darkest shade of black terracotta rather than of the black dye color.
+        assert_eq!(map_color_at(&colors, 32, 32), 4 * 4 + 2);
+        assert_eq!(map_color_at(&colors, 96, 32), 8 * 4 + 2);
+        assert_eq!(map_color_at(&colors, 32, 96), 51 * 4 + 3);
+        assert_eq!(map_color_at(&colors, 96, 96), 7 * 4 + 2);
+    }
+
+    #[test]
+    fn transparent_pixels_map_to_transparent_color() {
+        let cover = CoverArt::decode(TRANSPARENT_PNG).unwrap();
+        let colors = cover.to_map_colors();
+
+        // Fixture: transparent left half, opaque white right half
+        assert_eq!(map_color_at(&colors, 16, 64), 0);
+        assert_eq!(map_color_at(&colors, 112, 64), 8 * 4 + 2);
+    }
+
+    #[test]
+    fn png_round_trips() {
+        let cover = CoverArt::decode(QUADRANTS_JPG).unwrap();
+        let png = cover.to_png().unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+
+        let decoded = CoverArt::decode(&png).unwrap();
+        assert_eq!(decoded.image, cover.image);
+    }
+}
@@ -0,0 +1,97 @@
+use crate::client_channel::ClientChannel;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+use warp::ws::Message;
+
+/// Number of recent events kept for observers resuming with `Last-Event-ID`.
+const BACKLOG_LEN: usize = 64;
+
+/// A control message of a device, numbered so observers can tell which ones they missed.
+#[derive(Debug, Clone)]
+pub struct DeviceEvent {
+    pub id: u64,
+    /// The message `type`, e.g. `player_event`.
+    pub event_type: String,
+    pub json: Arc<str>,
+}
+
+struct Backlog {
+    next_id: u64,
+    events: VecDeque<DeviceEvent>,
+}
+
+/// Control messages of one device, sent to the WebSocket client owning it and published to
+/// observers such as the SSE endpoint.
+///
+/// Audio is not part of this; sinks write to the [`ClientChannel`] directly.
+#[derive(Clone)]
+pub struct DeviceEvents {
+    channel: ClientChannel,
+    backlog: Arc<Mutex<Backlog>>,
+    sender: broadcast::Sender<DeviceEvent>,
+}
+
+impl Default for DeviceEvents {
+    fn default() -> Self {
+        Self::new(ClientChannel::default())
+    }
+}
+
+impl DeviceEvents {
+    pub fn new(channel: ClientChannel) -> Self {
+        let (sender, _) = broadcast::channel(BACKLOG_LEN);
+        Self {
+            channel,
+            backlog: Arc::new(Mutex::new(Backlog {
+                next_id: 1,
+                events: VecDeque::with_capacity(BACKLOG_LEN),
+            })),
+            sender,
+        }
+    }
+
+    pub fn channel(&self) -> &ClientChannel {
+        &self.channel
+    }
+
+    pub fn send_json(&self, value: &serde_json::Value) {
+        let Ok(json) = serde_json::to_string(value) else {
+            return;
+        };
+        self.channel.send(Message::text(json.clone()));
+
+        let mut backlog = self.backlog.lock().unwrap();
+        let event = DeviceEvent {
+            id: backlog.next_id,
+            event_type: value["type"].as_str().unwrap_or("message").to_string(),
+            json: json.into(),
+        };
+        backlog.next_id += 1;
+        if backlog.events.len() == BACKLOG_LEN {
+            backlog.events.pop_front();
+        }
+        backlog.events.push_back(event.clone());
+        // Publishing under the lock keeps subscribers from seeing an event twice or not at all
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribes to new events, returning the backlogged events after `last_event_id` first.
+    /// Without `last_event_id` only new events are delivered.
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<u64>,
+    ) -> (Vec<DeviceEvent>, broadcast::Receiver<DeviceEvent>) {
+        let backlog = self.backlog.lock().unwrap();
+        let missed = match last_event_id {
+            Some(last_event_id) => backlog
+                .events
+                .iter()
+                .filter(|event| event.id > last_event_id)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        (missed, self.sender.subscribe())
+    }
+}
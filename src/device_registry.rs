@@ -0,0 +1,283 @@
+use crate::client_channel::{ClientChannel, OutgoingSender};
+use crate::device_events::DeviceEvents;
+use crate::spotify::{RemovalReason, SpotifyClient};
+use crate::ws_sink::AudioFraming;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Devices created by one client, kept alive across reconnects of that client.
+pub struct ClientSession {
+    /// Sequential id used to label metrics, unlike the resume token it is not a secret.
+    pub id: u64,
+    pub resume_token: String,
+    /// Identity the session was created under, the only one that may resume it.
+    pub client_id: String,
+    pub channel: ClientChannel,
+    pub devices: HashMap<String, SpotifyClient>,
+    next_stream_index: u16,
+    /// Bumped on every attach so a stale connection can't detach its successor.
+    generation: u64,
+}
+
+impl ClientSession {
+    fn new(id: u64, client_id: &str) -> Self {
+        Self {
+            id,
+            resume_token: Uuid::new_v4().to_string(),
+            client_id: client_id.to_string(),
+            channel: ClientChannel::default(),
+            devices: HashMap::new(),
+            next_stream_index: 0,
+            generation: 0,
+        }
+    }
+
+    pub fn next_stream_index(&mut self) -> u16 {
+        let stream_index = self.next_stream_index;
+        self.next_stream_index = stream_index.wrapping_add(1);
+        stream_index
+    }
+}
+
+/// Index entry that lets a device be found without locking every session.
+#[derive(Clone)]
+pub struct DeviceEntry {
+    pub device_id: String,
+    pub device_name: String,
+    pub session: Arc<Mutex<ClientSession>>,
+    pub session_id: u64,
+    /// `client_id` of the client that created the device, the only one allowed to use it.
+    pub owner: String,
+    pub events: DeviceEvents,
+}
+
+impl DeviceEntry {
+    /// Whether the client owning the device currently has a socket attached.
+    pub fn is_connected(&self) -> bool {
+        self.events.channel().is_attached()
+    }
+}
+
+pub struct AttachedSession {
+    pub session: Arc<Mutex<ClientSession>>,
+    pub generation: u64,
+    pub resumed: bool,
+}
+
+/// Server wide registry of client sessions, keyed by resume token, and of their devices.
+#[derive(Clone)]
+pub struct DeviceRegistry {
+    sessions: Arc<Mutex<HashMap<String, Arc<Mutex<ClientSession>>>>>,
+    /// Never held across an await, so a plain mutex is enough
+    devices: Arc<std::sync::Mutex<HashMap<String, DeviceEntry>>>,
+    grace_period: Duration,
+    next_session_id: Arc<AtomicU64>,
+}
+
+impl DeviceRegistry {
+    pub fn new(grace_period: Duration) -> Self {
+        Self {
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            devices: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            grace_period,
+            next_session_id: Arc::new(AtomicU64::new(1)),
+        }
+    }
+
+    /// Makes a device of `session` reachable by id.
+    pub fn register_device(
+        &self,
+        session: &Arc<Mutex<ClientSession>>,
+        session_id: u64,
+        events: &DeviceEvents,
+        device_id: &str,
+        device_name: &str,
+        owner: &str,
+    ) {
+        self.devices.lock().unwrap().insert(
+            device_id.to_string(),
+            DeviceEntry {
+                device_id: device_id.to_string(),
+                device_name: device_name.to_string(),
+                session: session.clone(),
+                session_id,
+                owner: owner.to_string(),
+                events: events.clone(),
+            },
+        );
+    }
+
+    pub fn unregister_device(&self, device_id: &str) {
+        self.devices.lock().unwrap().remove(device_id);
+    }
+
+    /// The device with `device_id`, if it exists and belongs to `client_id`.
+    pub fn owned_device(&self, device_id: &str, client_id: &str) -> Option<DeviceEntry> {
+        self.devices
+            .lock()
+            .unwrap()
+            .get(device_id)
+            .filter(|entry| entry.owner == client_id)
+            .cloned()
+    }
+
+    pub fn devices(&self) -> Vec<DeviceEntry> {
+        self.devices.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn devices_owned_by(&self, client_id: &str) -> Vec<DeviceEntry> {
+        self.devices
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| entry.owner == client_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Removes every session so none can be resumed, for server shutdown. Their devices are
+    /// left to the caller.
+    pub async fn drain(&self) -> Vec<Arc<Mutex<ClientSession>>> {
+        self.sessions
+            .lock()
+            .await
+            .drain()
+            .map(|(_, session)| session)
+            .collect()
+    }
+
+    /// Attaches a new connection of `client_id`, resuming the session of `resume_token` if it
+    /// is still alive and was created by the same client.
+    pub async fn attach(
+        &self,
+        resume_token: Option<&str>,
+        client_id: &str,
+        sender: OutgoingSender,
+        framing: AudioFraming,
+    ) -> AttachedSession {
+        let mut existing = match resume_token {
+            Some(token) => self.sessions.lock().await.get(token).cloned(),
+            None => None,
+        };
+        if let Some(session) = existing.take() {
+            let owner = session.lock().await.client_id.clone();
+            if owner == client_id {
+                existing = Some(session);
+            } else {
+                // Treated like an unknown token, so it doesn't tell whether the token exists
+                warn!("Client {client_id} tried to resume a session of client {owner}");
+            }
+        }
+        let resumed = existing.is_some();
+
+        let session = match existing {
+            Some(session) => session,
+            None => {
+                let session = ClientSession::new(
+                    self.next_session_id.fetch_add(1, Ordering::Relaxed),
+                    client_id,
+                );
+                let token = session.resume_token.clone();
+                let session = Arc::new(Mutex::new(session));
+                self.sessions.lock().await.insert(token, session.clone());
+                session
+            }
+        };
+
+        let generation = {
+            let mut state = session.lock().await;
+            state.generation += 1;
+            state.channel.attach(sender, framing);
+            state.generation
+        };
+
+        AttachedSession {
+            session,
+            generation,
+            resumed,
+        }
+    }
+
+    /// Detaches a closed connection and schedules its devices for shutdown unless the client
+    /// comes back within the grace period.
+    pub async fn detach(&self, session: &Arc<Mutex<ClientSession>>, generation: u64) {
+        let resume_token = {
+            let state = session.lock().await;
+            if state.generation != generation {
+                return;
+            }
+            state.channel.detach();
+            state.resume_token.clone()
+        };
+
+        info!(
+            "Keeping session devices for {:?} awaiting reconnect",
+            self.grace_period
+        );
+
+        let registry = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(registry.grace_period).await;
+            registry.expire(&resume_token, generation).await;
+        });
+    }
+
+    async fn expire(&self, resume_token: &str, generation: u64) {
+        let Some(session) = self.sessions.lock().await.get(resume_token).cloned() else {
+            return;
+        };
+
+        let mut state = session.lock().await;
+        if state.generation != generation || state.channel.is_attached() {
+            return;
+        }
+
+        self.sessions.lock().await.remove(resume_token);
+        info!(
+            "Client did not reconnect, shutting down {} device(s)",
+            state.devices.len()
+        );
+        for (device_id, mut device) in state.devices.drain() {
+            self.unregister_device(&device_id);
+            device.close(RemovalReason::SessionExpired).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_channel::{outgoing_queue, AudioOverflowPolicy};
+
+    async fn connect(
+        registry: &DeviceRegistry,
+        resume_token: Option<&str>,
+        client_id: &str,
+    ) -> AttachedSession {
+        let (sender, _receiver) = outgoing_queue(4, AudioOverflowPolicy::default());
+        registry
+            .attach(resume_token, client_id, sender, AudioFraming::Json)
+            .await
+    }
+
+    #[tokio::test]
+    async fn only_the_same_client_can_resume_a_session() {
+        let registry = DeviceRegistry::new(Duration::from_secs(30));
+        let first = connect(&registry, None, "player-1").await;
+        let resume_token = first.session.lock().await.resume_token.clone();
+
+        let other = connect(&registry, Some(&resume_token), "player-2").await;
+        assert!(!other.resumed);
+        assert!(!Arc::ptr_eq(&other.session, &first.session));
+        assert_eq!(other.session.lock().await.client_id, "player-2");
+
+        let resumed = connect(&registry, Some(&resume_token), "player-1").await;
+        assert!(resumed.resumed);
+        assert!(Arc::ptr_eq(&resumed.session, &first.session));
+    }
+}
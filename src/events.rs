@@ -0,0 +1,281 @@
+use librespot::playback::player::PlayerEvent;
+use serde::Serialize;
+
+/// JSON form of a librespot [`PlayerEvent`], sent as the `data` of a `player_event` message.
+///
+/// Serializes as `{"event_type": "<snake_case name>", "details": {...}}`. Event names are part
+/// of the protocol and must not change when librespot renames its variants.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event_type", content = "details", rename_all = "snake_case")]
+pub enum PlayerEventData {
+    /// A new play request was issued, subsequent events carry this id.
+    PlayRequestIdChanged { play_request_id: u64 },
+    /// Playback stopped and the track was unloaded.
+    Stopped {
+        play_request_id: u64,
+        track_id: String,
+    },
+    /// A track started loading.
+    Loading {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    /// The next track started preloading in the background.
+    Preloading { track_id: String },
+    /// Playback started or resumed.
+    Playing {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    /// Playback was paused.
+    Paused {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    /// The current track is close enough to its end that the next one should be preloaded.
+    TimeToPreloadNextTrack {
+        play_request_id: u64,
+        track_id: String,
+    },
+    /// The current track finished playing.
+    EndOfTrack {
+        play_request_id: u64,
+        track_id: String,
+    },
+    /// The requested track can't be played.
+    Unavailable {
+        play_request_id: u64,
+        track_id: String,
+    },
+    /// Volume changed, on a 0-65535 scale.
+    VolumeChanged { volume: u16 },
+    /// The reported position drifted from the actual one and was corrected.
+    PositionCorrection {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    /// Periodic position update while playing.
+    PositionChanged {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    /// Playback jumped to a new position.
+    Seeked {
+        play_request_id: u64,
+        track_id: String,
+        position_ms: u32,
+    },
+    /// A different track became current.
+    TrackChanged {
+        track_id: String,
+        name: String,
+        duration_ms: u32,
+    },
+    /// A Spotify session was established for the device.
+    SessionConnected {
+        connection_id: String,
+        user_name: String,
+    },
+    /// The Spotify session of the device ended.
+    SessionDisconnected {
+        connection_id: String,
+        user_name: String,
+    },
+    /// A different Spotify client (app) took control of the device.
+    SessionClientChanged {
+        client_id: String,
+        client_name: String,
+        client_brand_name: String,
+        client_model_name: String,
+    },
+    /// Shuffle was turned on or off.
+    ShuffleChanged { shuffle: bool },
+    /// Repeat changed, `context` repeats the whole context and `track` the current track.
+    RepeatChanged { context: bool, track: bool },
+    /// Autoplay of similar tracks once the context ends was turned on or off.
+    AutoPlayChanged { auto_play: bool },
+    /// Skipping of explicit tracks was turned on or off.
+    FilterExplicitContentChanged { filter: bool },
+}
+
+impl From<&PlayerEvent> for PlayerEventData {
+    fn from(event: &PlayerEvent) -> Self {
+        match event {
+            PlayerEvent::PlayRequestIdChanged { play_request_id } => Self::PlayRequestIdChanged {
+                play_request_id: *play_request_id,
+            },
+            PlayerEvent::Stopped {
+                play_request_id,
+                track_id,
+            } => Self::Stopped {
+                play_request_id: *play_request_id,
+                track_id: track_id.to_string(),
+            },
+            PlayerEvent::Loading {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => Self::Loading {
+                play_request_id: *play_request_id,
+                track_id: track_id.to_string(),
+                position_ms: *position_ms,
+            },
+            PlayerEvent::Preloading { track_id } => Self::Preloading {
+                track_id: track_id.to_string(),
+            },
+            PlayerEvent::Playing {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => Self::Playing {
+                play_request_id: *play_request_id,
+                track_id: track_id.to_string(),
+                position_ms: *position_ms,
+            },
+            PlayerEvent::Paused {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => Self::Paused {
+                play_request_id: *play_request_id,
+                track_id: track_id.to_string(),
+                position_ms: *position_ms,
+            },
+            PlayerEvent::TimeToPreloadNextTrack {
+                play_request_id,
+                track_id,
+            } => Self::TimeToPreloadNextTrack {
+                play_request_id: *play_request_id,
+                track_id: track_id.to_string(),
+            },
+            PlayerEvent::EndOfTrack {
+                play_request_id,
+                track_id,
+            } => Self::EndOfTrack {
+                play_request_id: *play_request_id,
+                track_id: track_id.to_string(),
+            },
+            PlayerEvent::Unavailable {
+                play_request_id,
+                track_id,
+            } => Self::Unavailable {
+                play_request_id: *play_request_id,
+                track_id: track_id.to_string(),
+            },
+            PlayerEvent::VolumeChanged { volume } => Self::VolumeChanged { volume: *volume },
+            PlayerEvent::PositionCorrection {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => Self::PositionCorrection {
+                play_request_id: *play_request_id,
+                track_id: track_id.to_string(),
+                position_ms: *position_ms,
+            },
+            PlayerEvent::PositionChanged {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => Self::PositionChanged {
+                play_request_id: *play_request_id,
+                track_id: track_id.to_string(),
+                position_ms: *position_ms,
+            },
+            PlayerEvent::Seeked {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => Self::Seeked {
+                play_request_id: *play_request_id,
+                track_id: track_id.to_string(),
+                position_ms: *position_ms,
+            },
+            PlayerEvent::TrackChanged { audio_item } => Self::TrackChanged {
+                track_id: audio_item.track_id.to_string(),
+                name: audio_item.name.clone(),
+                duration_ms: audio_item.duration_ms,
+            },
+            PlayerEvent::SessionConnected {
+                connection_id,
+                user_name,
+            } => Self::SessionConnected {
+                connection_id: connection_id.clone(),
+                user_name: user_name.clone(),
+            },
+            PlayerEvent::SessionDisconnected {
+                connection_id,
+                user_name,
+            } => Self::SessionDisconnected {
+                connection_id: connection_id.clone(),
+                user_name: user_name.clone(),
+            },
+            PlayerEvent::SessionClientChanged {
+                client_id,
+                client_name,
+                client_brand_name,
+                client_model_name,
+            } => Self::SessionClientChanged {
+                client_id: client_id.clone(),
+                client_name: client_name.clone(),
+                client_brand_name: client_brand_name.clone(),
+                client_model_name: client_model_name.clone(),
+            },
+            PlayerEvent::ShuffleChanged { shuffle } => Self::ShuffleChanged { shuffle: *shuffle },
+            PlayerEvent::RepeatChanged { context, track } => Self::RepeatChanged {
+                context: *context,
+                track: *track,
+            },
+            PlayerEvent::AutoPlayChanged { auto_play } => Self::AutoPlayChanged {
+                auto_play: *auto_play,
+            },
+            PlayerEvent::FilterExplicitContentChanged { filter } => {
+                Self::FilterExplicitContentChanged { filter: *filter }
+            }
+        }
+    }
+}
+
+pub fn player_event_message(device_id: &str, event: &PlayerEvent) -> serde_json::Value {
+    serde_json::json!({
+        "type": "player_event",
+        "device_id": device_id,
+        "data": PlayerEventData::from(event),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn setting_changes_keep_their_protocol_names() {
+        let cases = [
+            (
+                PlayerEvent::ShuffleChanged { shuffle: true },
+                json!({ "event_type": "shuffle_changed", "details": { "shuffle": true } }),
+            ),
+            (
+                PlayerEvent::AutoPlayChanged { auto_play: false },
+                json!({ "event_type": "auto_play_changed", "details": { "auto_play": false } }),
+            ),
+            (
+                PlayerEvent::FilterExplicitContentChanged { filter: true },
+                json!({
+                    "event_type": "filter_explicit_content_changed",
+                    "details": { "filter": true },
+                }),
+            ),
+        ];
+
+        for (event, expected) in cases {
+            let message = player_event_message("device-a", &event);
+            assert_eq!(message["data"], expected);
+        }
+    }
+}
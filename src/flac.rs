@@ -0,0 +1,558 @@
+/// Highest fixed predictor order FLAC defines.
+const MAX_FIXED_ORDER: usize = 4;
+
+/// Highest Rice partition order tried, 256 partitions.
+const MAX_PARTITION_ORDER: u32 = 8;
+
+/// Highest Rice parameter, the 5 bit parameter with 31 reserved as escape code.
+const MAX_RICE_PARAMETER: u32 = 30;
+
+/// Encodes blocks of interleaved integer samples to FLAC frames.
+///
+/// Every block becomes one frame that carries its own sample rate, channel count and bit
+/// depth, so a frame can be decoded without the ones before it or the stream header. Frames
+/// use fixed predictors and Rice coded residuals, with the best stereo decorrelation picked
+/// per frame.
+pub struct FlacEncoder {
+    sample_rate: u32,
+    channels: u8,
+    bits_per_sample: u32,
+    block_size: u16,
+    frame_number: u32,
+}
+
+impl FlacEncoder {
+    /// `bits_per_sample` is 16 or 24, `channels` 1 or 2 and `block_size` is the number of
+    /// samples per channel in every frame.
+    pub fn new(sample_rate: u32, channels: u8, bits_per_sample: u32, block_size: u16) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            bits_per_sample,
+            block_size,
+            frame_number: 0,
+        }
+    }
+
+    /// Starts a new stream, frame numbers start over at 0.
+    pub fn reset(&mut self) {
+        self.frame_number = 0;
+    }
+
+    /// `fLaC` marker and STREAMINFO block, for decoders that want a complete FLAC stream.
+    pub fn stream_header(&self) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.write(u64::from(u32::from_be_bytes(*b"fLaC")), 32);
+        // Last metadata block, type STREAMINFO, 34 bytes long
+        writer.write(1, 1);
+        writer.write(0, 7);
+        writer.write(34, 24);
+        writer.write(self.block_size.into(), 16);
+        writer.write(self.block_size.into(), 16);
+        // Frame sizes, total samples and MD5 are unknown for a live stream
+        writer.write(0, 24);
+        writer.write(0, 24);
+        writer.write(self.sample_rate.into(), 20);
+        writer.write(u64::from(self.channels - 1), 3);
+        writer.write(u64::from(self.bits_per_sample - 1), 5);
+        writer.write(0, 4);
+        writer.write(0, 32);
+        for _ in 0..4 {
+            writer.write(0, 32);
+        }
+        writer.into_bytes()
+    }
+
+    /// Encodes one block of `block_size` interleaved samples into a frame.
+    pub fn encode(&mut self, samples: &[i32]) -> Vec<u8> {
+        let channels = usize::from(self.channels);
+        let block_size = samples.len() / channels;
+        let bps = self.bits_per_sample;
+
+        let channel = |index: usize| -> Vec<i64> {
+            samples
+                .iter()
+                .skip(index)
+                .step_by(channels)
+                .map(|&sample| i64::from(sample))
+                .collect()
+        };
+
+        let signals = if channels == 2 {
+            let left = channel(0);
+            let right = channel(1);
+            let side = left.iter().zip(&right).map(|(l, r)| l - r).collect();
+            let mid = left.iter().zip(&right).map(|(l, r)| (l + r) >> 1).collect();
+            vec![left, right, side, mid]
+        } else {
+            vec![channel(0)]
+        };
+
+        let (assignment, subframes) = if channels == 2 {
+            // Left, right, side and mid, the difference of two channels needs one more bit
+            let mut planned: Vec<Option<Subframe>> = signals
+                .iter()
+                .zip([bps, bps, bps + 1, bps])
+                .map(|(signal, bps)| Some(Subframe::plan(signal, bps)))
+                .collect();
+            let bits = |i: usize| planned[i].as_ref().unwrap().bits;
+
+            let (assignment, first, second) = [
+                (0b0001, 0, 1),
+                (0b1000, 0, 2),
+                (0b1001, 2, 1),
+                (0b1010, 3, 2),
+            ]
+            .into_iter()
+            .min_by_key(|&(_, first, second)| bits(first) + bits(second))
+            .unwrap();
+            let first = planned[first].take().unwrap();
+            let second = planned[second].take().unwrap();
+            (assignment, vec![first, second])
+        } else {
+            (0b0000, vec![Subframe::plan(&signals[0], bps)])
+        };
+
+        let mut writer = BitWriter::default();
+        self.write_frame_header(&mut writer, block_size, assignment);
+        for subframe in &subframes {
+            subframe.write(&mut writer);
+        }
+        writer.align();
+        let crc = crc16(&writer.bytes);
+        writer.write(crc.into(), 16);
+
+        self.frame_number = (self.frame_number + 1) & 0x7fff_ffff;
+        writer.into_bytes()
+    }
+
+    fn write_frame_header(&self, writer: &mut BitWriter, block_size: usize, assignment: u64) {
+        // Sync code, reserved bit and fixed block size strategy
+        writer.write(0b1111_1111_1111_1000, 16);
+
+        let block_size_code = if block_size <= 256 { 0b0110 } else { 0b0111 };
+        writer.write(block_size_code, 4);
+        let sample_rate_code = match self.sample_rate {
+            88_200 => 0b0001,
+            8_000 => 0b0100,
+            16_000 => 0b0101,
+            22_050 => 0b0110,
+            24_000 => 0b0111,
+            32_000 => 0b1000,
+            44_100 => 0b1001,
+            48_000 => 0b1010,
+            96_000 => 0b1011,
+            // In Hz at the end of the header
+            _ => 0b1101,
+        };
+        writer.write(sample_rate_code, 4);
+
+        writer.write(assignment, 4);
+        let sample_size_code = if self.bits_per_sample == 24 {
+            0b110
+        } else {
+            0b100
+        };
+        writer.write(sample_size_code, 3);
+        writer.write(0, 1);
+
+        write_utf8_number(writer, self.frame_number);
+        if block_size_code == 0b0110 {
+            writer.write(block_size as u64 - 1, 8);
+        } else {
+            writer.write(block_size as u64 - 1, 16);
+        }
+        if sample_rate_code == 0b1101 {
+            writer.write(self.sample_rate.into(), 16);
+        }
+
+        let crc = crc8(&writer.bytes);
+        writer.write(crc.into(), 8);
+    }
+}
+
+enum SubframeKind {
+    Constant,
+    Verbatim,
+    Fixed {
+        order: usize,
+        residual: Vec<i64>,
+        partition_order: u32,
+        parameters: Vec<u32>,
+    },
+}
+
+/// A subframe chosen for one channel, with its size in bits.
+struct Subframe<'a> {
+    samples: &'a [i64],
+    bps: u32,
+    kind: SubframeKind,
+    bits: u64,
+}
+
+impl<'a> Subframe<'a> {
+    /// Picks the smallest encoding of `samples`.
+    fn plan(samples: &'a [i64], bps: u32) -> Self {
+        // Every subframe starts with an 8 bit header
+        if samples.iter().all(|&sample| sample == samples[0]) {
+            return Self {
+                samples,
+                bps,
+                kind: SubframeKind::Constant,
+                bits: 8 + u64::from(bps),
+            };
+        }
+
+        let mut best = Self {
+            samples,
+            bps,
+            kind: SubframeKind::Verbatim,
+            bits: 8 + u64::from(bps) * samples.len() as u64,
+        };
+
+        let mut residual = samples.to_vec();
+        for order in 0..=MAX_FIXED_ORDER.min(samples.len() - 1) {
+            // The residual of order n is the nth difference of the samples
+            if order > 0 {
+                for i in (order..samples.len()).rev() {
+                    residual[i] -= residual[i - 1];
+                }
+            }
+
+            let (partition_order, parameters, residual_bits) =
+                plan_rice(&residual[order..], samples.len(), order);
+            let bits = 8 + u64::from(bps) * order as u64 + residual_bits;
+            if bits < best.bits {
+                best = Self {
+                    samples,
+                    bps,
+                    kind: SubframeKind::Fixed {
+                        order,
+                        residual: residual[order..].to_vec(),
+                        partition_order,
+                        parameters,
+                    },
+                    bits,
+                };
+            }
+        }
+        best
+    }
+
+    fn write(&self, writer: &mut BitWriter) {
+        // Zero padding bit, subframe type and no wasted bits
+        match &self.kind {
+            SubframeKind::Constant => {
+                writer.write(0b0000_0000, 8);
+                writer.write_signed(self.samples[0], self.bps);
+            }
+            SubframeKind::Verbatim => {
+                writer.write(0b0000_0010, 8);
+                for &sample in self.samples {
+                    writer.write_signed(sample, self.bps);
+                }
+            }
+            SubframeKind::Fixed {
+                order,
+                residual,
+                partition_order,
+                parameters,
+            } => {
+                writer.write(0b0001_0000 | (*order as u64) << 1, 8);
+                for &sample in &self.samples[..*order] {
+                    writer.write_signed(sample, self.bps);
+                }
+                write_rice(
+                    writer,
+                    residual,
+                    self.samples.len(),
+                    *order,
+                    *partition_order,
+                    parameters,
+                );
+            }
+        }
+    }
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// Picks the Rice partition order and parameters with the smallest estimated size for the
+/// `residual` of a block of `block_size` samples, returning the size in bits.
+fn plan_rice(residual: &[i64], block_size: usize, order: usize) -> (u32, Vec<u32>, u64) {
+    let mut best: Option<(u32, Vec<u32>, u64)> = None;
+
+    for partition_order in 0..=MAX_PARTITION_ORDER {
+        let partitions = 1usize << partition_order;
+        let partition_len = block_size >> partition_order;
+        // The first partition loses the warm-up samples and can't be empty
+        if !block_size.is_multiple_of(partitions) || partition_len <= order {
+            break;
+        }
+
+        let mut parameters = Vec::with_capacity(partitions);
+        // Coding method, partition order and the 5 bit parameters assumed for now
+        let mut bits = 2 + 4;
+        let mut start = 0;
+        for partition in 0..partitions {
+            let len = if partition == 0 {
+                partition_len - order
+            } else {
+                partition_len
+            };
+            let sum: u64 = residual[start..start + len]
+                .iter()
+                .map(|&r| zigzag(r))
+                .sum();
+            start += len;
+
+            let (parameter, partition_bits) = (0..=MAX_RICE_PARAMETER)
+                .map(|k| (k, len as u64 * (u64::from(k) + 1) + (sum >> k)))
+                .min_by_key(|&(_, bits)| bits)
+                .unwrap();
+            parameters.push(parameter);
+            bits += 5 + partition_bits;
+        }
+
+        if best
+            .as_ref()
+            .is_none_or(|(_, _, best_bits)| bits < *best_bits)
+        {
+            best = Some((partition_order, parameters, bits));
+        }
+    }
+
+    best.unwrap()
+}
+
+fn write_rice(
+    writer: &mut BitWriter,
+    residual: &[i64],
+    block_size: usize,
+    order: usize,
+    partition_order: u32,
+    parameters: &[u32],
+) {
+    // The 4 bit parameter method unless a parameter needs 5 bits
+    let wide = parameters.iter().any(|&k| k >= 15);
+    writer.write(u64::from(wide), 2);
+    writer.write(partition_order.into(), 4);
+
+    let partition_len = block_size >> partition_order;
+    let mut start = 0;
+    for (partition, &k) in parameters.iter().enumerate() {
+        let len = if partition == 0 {
+            partition_len - order
+        } else {
+            partition_len
+        };
+        writer.write(k.into(), if wide { 5 } else { 4 });
+        for &r in &residual[start..start + len] {
+            let value = zigzag(r);
+            writer.write_unary(value >> k);
+            writer.write(value & ((1 << k) - 1), k);
+        }
+        start += len;
+    }
+}
+
+/// Frame number in the UTF-8 like variable length coding of FLAC frame headers.
+fn write_utf8_number(writer: &mut BitWriter, number: u32) {
+    let number = u64::from(number);
+    if number < 0x80 {
+        writer.write(number, 8);
+        return;
+    }
+
+    let continuation_bytes = match number {
+        0..0x800 => 1,
+        0x800..0x1_0000 => 2,
+        0x1_0000..0x20_0000 => 3,
+        0x20_0000..0x400_0000 => 4,
+        _ => 5,
+    };
+    let lead_marker = !(0xffu64 >> (continuation_bytes + 1)) & 0xff;
+    writer.write(lead_marker | number >> (6 * continuation_bytes), 8);
+    for i in (0..continuation_bytes).rev() {
+        writer.write(0x80 | (number >> (6 * i)) & 0x3f, 8);
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for &byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// Writes values most significant bit first.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `bits` bits of `value`, at most 32 at a time.
+    fn write(&mut self, value: u64, bits: u32) {
+        debug_assert!(bits <= 32);
+        if bits == 0 {
+            return;
+        }
+        self.pending = self.pending << bits | (value & ((1 << bits) - 1));
+        self.pending_bits += bits;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+        self.pending &= (1 << self.pending_bits) - 1;
+    }
+
+    /// Writes `value` as a two's complement number of `bits` bits.
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64, bits);
+    }
+
+    /// Writes `value` zeros followed by a one.
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    /// Pads with zeros to the next byte boundary.
+    fn align(&mut self) {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claxon::frame::FrameReader;
+    use claxon::FlacReader;
+    use std::f64::consts::PI;
+    use std::io::Cursor;
+
+    /// Interleaved test signal: a chord plus deterministic noise, differing per channel.
+    fn signal(channels: usize, frames: usize, bits: u32) -> Vec<i32> {
+        let amplitude = f64::from((1 << (bits - 1)) - 1);
+        let mut noise = 0x2545_f491u32;
+        (0..frames * channels)
+            .map(|i| {
+                let t = (i / channels) as f64 / 44_100.0;
+                let ch = (i % channels) as f64;
+                noise ^= noise << 13;
+                noise ^= noise >> 17;
+                noise ^= noise << 5;
+                let value = 0.4 * (2.0 * PI * 440.0 * t).sin()
+                    + 0.3 * (2.0 * PI * (660.0 + 110.0 * ch) * t).sin()
+                    + 0.05 * (f64::from(noise) / f64::from(u32::MAX) - 0.5);
+                (value * amplitude) as i32
+            })
+            .collect()
+    }
+
+    fn decode_stream(bytes: &[u8]) -> Vec<i32> {
+        let mut reader = FlacReader::new(Cursor::new(bytes)).unwrap();
+        reader.samples().map(|sample| sample.unwrap()).collect()
+    }
+
+    fn round_trip(channels: u8, bits: u32, sample_rate: u32, block_size: u16) {
+        let frames = usize::from(block_size) * 20;
+        let input = signal(channels.into(), frames, bits);
+        let mut encoder = FlacEncoder::new(sample_rate, channels, bits, block_size);
+
+        let mut stream = encoder.stream_header();
+        let chunk = usize::from(block_size) * usize::from(channels);
+        for block in input.chunks(chunk) {
+            stream.extend(encoder.encode(block));
+        }
+
+        assert!(stream.len() < input.len() * bits as usize / 8);
+        assert_eq!(decode_stream(&stream), input);
+    }
+
+    #[test]
+    fn round_trips_stereo_16_bit() {
+        round_trip(2, 16, 44_100, 2205);
+    }
+
+    #[test]
+    fn round_trips_mono_24_bit() {
+        round_trip(1, 24, 48_000, 960);
+    }
+
+    #[test]
+    fn round_trips_small_blocks_at_unlisted_rates() {
+        round_trip(2, 16, 11_025, 220);
+    }
+
+    #[test]
+    fn round_trips_silence_and_full_scale() {
+        let mut input = vec![0; 2205 * 2];
+        input.extend((0..2205 * 2).map(|i| if i % 4 < 2 { i16::MAX } else { i16::MIN } as i32));
+        input.extend((0..2205 * 2).map(|i| if i % 2 == 0 { i16::MAX } else { i16::MIN } as i32));
+
+        let mut encoder = FlacEncoder::new(44_100, 2, 16, 2205);
+        let mut stream = encoder.stream_header();
+        for block in input.chunks(2205 * 2) {
+            stream.extend(encoder.encode(block));
+        }
+        assert_eq!(decode_stream(&stream), input);
+    }
+
+    #[test]
+    fn frames_decode_on_their_own() {
+        let input = signal(2, 2205 * 300, 16);
+        let mut encoder = FlacEncoder::new(44_100, 2, 16, 2205);
+        let frames: Vec<Vec<u8>> = input.chunks(2205 * 2).map(|b| encoder.encode(b)).collect();
+
+        // A late frame, with frame numbers past the one byte coding, without what came before
+        let index = 250;
+        let mut reader = FrameReader::new(Cursor::new(&frames[index]));
+        let block = reader.read_next_or_eof(Vec::new()).unwrap().unwrap();
+
+        assert_eq!(block.time(), index as u64 * 2205);
+        let decoded: Vec<i32> = block
+            .stereo_samples()
+            .flat_map(|(left, right)| [left, right])
+            .collect();
+        assert_eq!(decoded, input[index * 2205 * 2..(index + 1) * 2205 * 2]);
+    }
+}
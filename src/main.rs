@@ -1,14 +1,36 @@
 use anyhow::Result;
 use clap::Parser;
-use log::info;
+use log::{info, warn};
 
+mod auth;
+mod client_channel;
 mod command_manager;
 mod commands;
+mod cover_art;
+mod device_events;
+mod device_registry;
+mod events;
+mod flac;
+mod metadata;
+mod metrics;
+mod opus;
+mod pacing;
+mod playback_state;
+mod resample;
+mod rest;
 mod server;
 mod spotify;
+mod tls;
+mod track_position;
 mod ws_sink;
 
-use server::SpotifyServer;
+use auth::{ApiKeyAuthenticator, Authenticator, ClientAuth, HmacTokenAuthenticator};
+use client_channel::AudioOverflowPolicy;
+use server::{ServerConfig, SpotifyServer};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tls::TlsConfig;
 
 #[derive(Parser)]
 #[command(name = "blockyspot")]
@@ -18,6 +40,54 @@ struct Args {
     /// Port to run the WebSocket server on
     #[arg(short, long, default_value_t = 8888)]
     port: u16,
+
+    /// Address to listen on, IPv4 or IPv6 with an optional port. May be given multiple times
+    #[arg(long, default_value = "127.0.0.1", value_delimiter = ',')]
+    bind: Vec<String>,
+
+    /// PEM certificate chain to serve wss:// with, reloaded on SIGHUP
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Seconds to keep the devices of a disconnected client alive for it to reconnect
+    #[arg(long, default_value_t = 30)]
+    resume_grace_period: u64,
+
+    /// API key clients can authenticate with, may be given multiple times
+    #[arg(long = "api-key", env = "BLOCKYSPOT_API_KEYS", value_delimiter = ',')]
+    api_keys: Vec<String>,
+
+    /// Shared secret for verifying HMAC signed client tokens
+    #[arg(long, env = "BLOCKYSPOT_TOKEN_SECRET")]
+    token_secret: Option<String>,
+
+    /// Longest lifetime in seconds accepted for signed client tokens
+    #[arg(long, default_value_t = 300)]
+    token_max_lifetime: u64,
+
+    /// Browser origin allowed to connect, may be given multiple times (default: any origin)
+    #[arg(long = "allowed-origin", value_delimiter = ',')]
+    allowed_origins: Vec<String>,
+
+    /// Seconds to keep serving on SIGTERM/Ctrl-C after /readyz starts reporting not ready
+    #[arg(long, default_value_t = 5)]
+    readiness_drain: u64,
+
+    /// Seconds to wait on SIGTERM/Ctrl-C for devices to leave Spotify Connect before exiting
+    #[arg(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+
+    /// Audio frames queued per connection before --audio-overflow applies
+    #[arg(long, default_value_t = 50)]
+    audio_queue_len: usize,
+
+    /// What to do when a client falls behind and its audio queue is full
+    #[arg(long, value_enum, default_value_t = AudioOverflowPolicy::DropOldest)]
+    audio_overflow: AudioOverflowPolicy,
 }
 
 #[tokio::main]
@@ -28,9 +98,115 @@ async fn main() -> Result<()> {
 
     info!("Starting BlockySpot...");
 
-    let server = SpotifyServer::new();
-    info!("Starting WebSocket server on port {}...", args.port);
-    server.start(args.port).await;
+    let mut authenticators: Vec<Box<dyn Authenticator>> = Vec::new();
+    if !args.api_keys.is_empty() {
+        authenticators.push(Box::new(ApiKeyAuthenticator::new(args.api_keys)));
+    }
+    if let Some(secret) = args.token_secret {
+        authenticators.push(Box::new(HmacTokenAuthenticator::new(
+            secret,
+            Duration::from_secs(args.token_max_lifetime),
+        )));
+    }
+
+    let server = SpotifyServer::new(ServerConfig {
+        resume_grace_period: Duration::from_secs(args.resume_grace_period),
+        auth: ClientAuth::new(authenticators),
+        allowed_origins: args.allowed_origins,
+        readiness_drain: Duration::from_secs(args.readiness_drain),
+        shutdown_timeout: Duration::from_secs(args.shutdown_timeout),
+        audio_queue_len: args.audio_queue_len.max(1),
+        audio_overflow: args.audio_overflow,
+    });
+    let addrs = args
+        .bind
+        .iter()
+        .map(|bind| parse_bind_address(bind, args.port))
+        .collect::<Result<Vec<_>>>()?;
+    let tls = match (args.tls_cert, args.tls_key) {
+        (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+            cert_path,
+            key_path,
+        }),
+        _ => None,
+    };
+
+    info!("Starting WebSocket server...");
+    server.start(addrs, tls, shutdown_signal()).await?;
 
     Ok(())
 }
+
+/// Resolves on Ctrl-C, or on SIGTERM on unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = sigterm.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("Failed to listen for SIGTERM: {e}"),
+        }
+    }
+
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Failed to listen for Ctrl-C, shutdown only by killing the process: {e}");
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Parses `ip` or `ip:port` (`[ip]:port` for IPv6), using `default_port` when none is given.
+fn parse_bind_address(bind: &str, default_port: u16) -> Result<SocketAddr> {
+    if let Ok(addr) = bind.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+
+    let ip = bind
+        .parse::<IpAddr>()
+        .map_err(|_| anyhow::anyhow!("Invalid bind address: {bind}"))?;
+    Ok(SocketAddr::new(ip, default_port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bind: &str) -> Result<String> {
+        parse_bind_address(bind, 8888).map(|addr| addr.to_string())
+    }
+
+    #[test]
+    fn ip_uses_the_default_port() {
+        assert_eq!(parse("127.0.0.1").unwrap(), "127.0.0.1:8888");
+        assert_eq!(parse("::").unwrap(), "[::]:8888");
+        assert_eq!(parse("::1").unwrap(), "[::1]:8888");
+    }
+
+    #[test]
+    fn explicit_port_wins() {
+        assert_eq!(parse("0.0.0.0:9000").unwrap(), "0.0.0.0:9000");
+        assert_eq!(parse("[::1]:9000").unwrap(), "[::1]:9000");
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        for bind in [
+            "",
+            "localhost",
+            "localhost:9000",
+            "127.0.0.1:",
+            "127.0.0.1:70000",
+            "[::1]",
+            "::1:9000:x",
+        ] {
+            let error = parse(bind).unwrap_err();
+            assert_eq!(error.to_string(), format!("Invalid bind address: {bind}"));
+        }
+    }
+}
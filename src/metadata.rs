@@ -0,0 +1,97 @@
+use crate::cover_art::{CoverArt, MAP_SIZE};
+use anyhow::Result;
+use librespot::core::file_id::FileId;
+use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
+use librespot::metadata::{Metadata, Track};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CoverImage {
+    /// Hex encoded file id, can be fetched through the session's CDN
+    pub id: String,
+    pub width: i32,
+    pub height: i32,
+    #[serde(skip)]
+    pub file_id: FileId,
+}
+
+/// Track details pushed as `track_metadata` events and returned by `GetTrackMetadata`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TrackMetadata {
+    pub track_id: String,
+    pub title: String,
+    pub artists: Vec<String>,
+    pub album: String,
+    pub duration_ms: u32,
+    pub covers: Vec<CoverImage>,
+    pub explicit: bool,
+}
+
+impl TrackMetadata {
+    pub async fn fetch(session: &Session, track_id: &SpotifyId) -> Result<Self> {
+        let track = Track::get(session, track_id)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to fetch metadata for {track_id}: {e}"))?;
+
+        Ok(Self {
+            track_id: track_id.to_string(),
+            title: track.name,
+            artists: track
+                .artists
+                .iter()
+                .map(|artist| artist.name.clone())
+                .collect(),
+            album: track.album.name,
+            duration_ms: track.duration.try_into().unwrap_or_default(),
+            covers: track
+                .album
+                .covers
+                .iter()
+                .map(|image| CoverImage {
+                    id: image.id.to_string(),
+                    width: image.width,
+                    height: image.height,
+                    file_id: image.id,
+                })
+                .collect(),
+            explicit: track.is_explicit,
+        })
+    }
+
+    /// Downloads the smallest cover that still fills a map, or the largest one available.
+    pub async fn fetch_cover_art(&self, session: &Session) -> Result<CoverArt> {
+        let fits_map = |cover: &&CoverImage| cover.width >= MAP_SIZE as i32;
+        let cover = self
+            .covers
+            .iter()
+            .filter(fits_map)
+            .min_by_key(|cover| cover.width)
+            .or_else(|| self.covers.iter().max_by_key(|cover| cover.width))
+            .ok_or_else(|| anyhow::anyhow!("Track {} has no cover art", self.track_id))?;
+
+        let bytes = session
+            .spclient()
+            .get_image(&cover.file_id)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to download cover {}: {e}", cover.id))?;
+
+        CoverArt::decode(&bytes)
+    }
+}
+
+pub fn track_metadata_message(device_id: &str, metadata: &TrackMetadata) -> serde_json::Value {
+    serde_json::json!({
+        "type": "track_metadata",
+        "device_id": device_id,
+        "data": metadata,
+    })
+}
+
+pub fn cover_art_message(device_id: &str, cover_art: serde_json::Value) -> serde_json::Value {
+    serde_json::json!({
+        "type": "cover_art",
+        "device_id": device_id,
+        "data": cover_art,
+    })
+}
@@ -0,0 +1,334 @@
+use crate::client_channel::OutgoingSender;
+use crate::device_registry::DeviceRegistry;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Server wide metrics, rendered in the Prometheus text format by the `/metrics` route.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// Why a device's Spirc task stopped, the `reason` label of `blockyspot_spirc_task_exits_total`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SpircExit {
+    /// Finished after the device was shut down.
+    Shutdown,
+    /// Finished while the device was still in use, e.g. because the session was lost.
+    Unexpected,
+    Panicked,
+    /// Did not finish within the shutdown timeout and was aborted.
+    Aborted,
+}
+
+impl SpircExit {
+    fn label(self) -> &'static str {
+        match self {
+            SpircExit::Shutdown => "shutdown",
+            SpircExit::Unexpected => "unexpected",
+            SpircExit::Panicked => "panicked",
+            SpircExit::Aborted => "aborted",
+        }
+    }
+}
+
+/// `command_type` label of commands that were rejected before they could run, e.g. because
+/// they could not be parsed.
+pub const INVALID_COMMAND: &str = "invalid";
+
+#[derive(Default)]
+struct CommandCounts {
+    executed: u64,
+    failed: u64,
+}
+
+/// Audio counters of one device, updated by its sink.
+#[derive(Default)]
+pub struct DeviceMetrics {
+    audio_bytes: AtomicU64,
+    audio_frames: AtomicU64,
+    dropped_frames: AtomicU64,
+    pacing_sleep_nanos: AtomicU64,
+}
+
+impl DeviceMetrics {
+    pub fn frame_sent(&self, bytes: usize) {
+        self.audio_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.audio_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn frame_dropped(&self) {
+        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn paced(&self, sleep: Duration) {
+        self.pacing_sleep_nanos
+            .fetch_add(sleep.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    /// Outgoing queue of every open connection, by session id.
+    connections: Mutex<BTreeMap<u64, OutgoingSender>>,
+    commands: Mutex<BTreeMap<&'static str, CommandCounts>>,
+    devices: Mutex<BTreeMap<String, Arc<DeviceMetrics>>>,
+    spirc_task_exits: Mutex<BTreeMap<SpircExit, u64>>,
+}
+
+impl Metrics {
+    pub fn connection_opened(&self, session_id: u64, sender: OutgoingSender) {
+        self.connections.lock().unwrap().insert(session_id, sender);
+    }
+
+    /// Forgets a closed connection unless a newer connection has resumed its session.
+    pub fn connection_closed(&self, session_id: u64, sender: &OutgoingSender) {
+        let mut connections = self.connections.lock().unwrap();
+        if connections
+            .get(&session_id)
+            .is_some_and(|current| current.same_queue(sender))
+        {
+            connections.remove(&session_id);
+        }
+    }
+
+    pub fn command_executed(&self, command_type: &'static str, success: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        let counts = commands.entry(command_type).or_default();
+        counts.executed += 1;
+        if !success {
+            counts.failed += 1;
+        }
+    }
+
+    /// Audio counters of `device_id`, created on first use.
+    pub fn device(&self, device_id: &str) -> Arc<DeviceMetrics> {
+        self.devices
+            .lock()
+            .unwrap()
+            .entry(device_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    pub fn remove_device(&self, device_id: &str) {
+        self.devices.lock().unwrap().remove(device_id);
+    }
+
+    pub fn spirc_task_exited(&self, exit: SpircExit) {
+        *self
+            .spirc_task_exits
+            .lock()
+            .unwrap()
+            .entry(exit)
+            .or_default() += 1;
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self, registry: &DeviceRegistry) -> String {
+        let mut out = String::new();
+
+        let connections = self.connections.lock().unwrap();
+        metric_header(
+            &mut out,
+            "blockyspot_connections",
+            "gauge",
+            "Open WebSocket connections",
+        );
+        let _ = writeln!(out, "blockyspot_connections {}", connections.len());
+
+        metric_header(
+            &mut out,
+            "blockyspot_send_queue_depth",
+            "gauge",
+            "Messages waiting to be written to a connection's socket, by queue",
+        );
+        for (session_id, sender) in connections.iter() {
+            let (control, audio) = sender.pending();
+            let _ = writeln!(
+                out,
+                "blockyspot_send_queue_depth{{session=\"{session_id}\",queue=\"control\"}} {control}"
+            );
+            let _ = writeln!(
+                out,
+                "blockyspot_send_queue_depth{{session=\"{session_id}\",queue=\"audio\"}} {audio}"
+            );
+        }
+        drop(connections);
+
+        let mut devices_per_session = BTreeMap::<u64, usize>::new();
+        for device in registry.devices() {
+            *devices_per_session.entry(device.session_id).or_default() += 1;
+        }
+        metric_header(
+            &mut out,
+            "blockyspot_session_devices",
+            "gauge",
+            "Devices owned by a client session",
+        );
+        for (session_id, count) in &devices_per_session {
+            let _ = writeln!(
+                out,
+                "blockyspot_session_devices{{session=\"{session_id}\"}} {count}"
+            );
+        }
+
+        let commands = self.commands.lock().unwrap();
+        metric_header(
+            &mut out,
+            "blockyspot_commands_total",
+            "counter",
+            "Commands executed, by command type",
+        );
+        for (command_type, counts) in commands.iter() {
+            let _ = writeln!(
+                out,
+                "blockyspot_commands_total{{command_type=\"{command_type}\"}} {}",
+                counts.executed
+            );
+        }
+        metric_header(
+            &mut out,
+            "blockyspot_commands_failed_total",
+            "counter",
+            "Commands that failed, by command type",
+        );
+        for (command_type, counts) in commands.iter() {
+            let _ = writeln!(
+                out,
+                "blockyspot_commands_failed_total{{command_type=\"{command_type}\"}} {}",
+                counts.failed
+            );
+        }
+        drop(commands);
+
+        let devices = self.devices.lock().unwrap();
+        metric_header(
+            &mut out,
+            "blockyspot_audio_bytes_total",
+            "counter",
+            "Audio payload bytes sent, by device",
+        );
+        for (device_id, device) in devices.iter() {
+            let _ = writeln!(
+                out,
+                "blockyspot_audio_bytes_total{{device_id=\"{device_id}\"}} {}",
+                device.audio_bytes.load(Ordering::Relaxed)
+            );
+        }
+        metric_header(
+            &mut out,
+            "blockyspot_audio_frames_total",
+            "counter",
+            "Audio frames sent, by device",
+        );
+        for (device_id, device) in devices.iter() {
+            let _ = writeln!(
+                out,
+                "blockyspot_audio_frames_total{{device_id=\"{device_id}\"}} {}",
+                device.audio_frames.load(Ordering::Relaxed)
+            );
+        }
+        metric_header(
+            &mut out,
+            "blockyspot_audio_frames_dropped_total",
+            "counter",
+            "Audio frames dropped because a client fell behind, by device",
+        );
+        for (device_id, device) in devices.iter() {
+            let _ = writeln!(
+                out,
+                "blockyspot_audio_frames_dropped_total{{device_id=\"{device_id}\"}} {}",
+                device.dropped_frames.load(Ordering::Relaxed)
+            );
+        }
+        metric_header(
+            &mut out,
+            "blockyspot_sink_pacing_sleep_seconds_total",
+            "counter",
+            "Time sinks slept to pace audio to real time, by device",
+        );
+        for (device_id, device) in devices.iter() {
+            let _ = writeln!(
+                out,
+                "blockyspot_sink_pacing_sleep_seconds_total{{device_id=\"{device_id}\"}} {}",
+                Duration::from_nanos(device.pacing_sleep_nanos.load(Ordering::Relaxed))
+                    .as_secs_f64()
+            );
+        }
+        drop(devices);
+
+        metric_header(
+            &mut out,
+            "blockyspot_spirc_task_exits_total",
+            "counter",
+            "Spirc tasks that stopped, by reason",
+        );
+        for (exit, count) in self.spirc_task_exits.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "blockyspot_spirc_task_exits_total{{reason=\"{}\"}} {count}",
+                exit.label()
+            );
+        }
+
+        out
+    }
+}
+
+fn metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_commands_and_dropped_frames() {
+        let metrics = Metrics::default();
+        metrics.command_executed("Play", true);
+        metrics.command_executed("Play", false);
+        metrics.command_executed(INVALID_COMMAND, false);
+        let device = metrics.device("device-a");
+        device.frame_sent(100);
+        device.frame_dropped();
+
+        let rendered = metrics.render(&DeviceRegistry::new(Duration::from_secs(30)));
+        let lines: Vec<_> = rendered.lines().collect();
+        for expected in [
+            "# TYPE blockyspot_commands_total counter",
+            "blockyspot_commands_total{command_type=\"Play\"} 2",
+            "blockyspot_commands_total{command_type=\"invalid\"} 1",
+            "# TYPE blockyspot_commands_failed_total counter",
+            "blockyspot_commands_failed_total{command_type=\"Play\"} 1",
+            "blockyspot_commands_failed_total{command_type=\"invalid\"} 1",
+            "# TYPE blockyspot_audio_frames_total counter",
+            "blockyspot_audio_frames_total{device_id=\"device-a\"} 1",
+            "blockyspot_audio_bytes_total{device_id=\"device-a\"} 100",
+            "# TYPE blockyspot_audio_frames_dropped_total counter",
+            "blockyspot_audio_frames_dropped_total{device_id=\"device-a\"} 1",
+            "# TYPE blockyspot_connections gauge",
+            "blockyspot_connections 0",
+        ] {
+            assert!(lines.contains(&expected), "missing {expected}\n{rendered}");
+        }
+    }
+
+    #[test]
+    fn removed_devices_are_not_rendered() {
+        let metrics = Metrics::default();
+        metrics.device("device-a").frame_dropped();
+        metrics.remove_device("device-a");
+
+        let rendered = metrics.render(&DeviceRegistry::new(Duration::from_secs(30)));
+        assert!(!rendered.contains("device-a"));
+        // Every metric is still described
+        assert!(rendered.contains("# TYPE blockyspot_audio_frames_dropped_total counter"));
+    }
+}
@@ -0,0 +1,154 @@
+use audiopus::coder::{Encoder, GenericCtl};
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use std::ops::RangeInclusive;
+
+/// Sample rates Opus can encode at that a sink can resample to.
+pub const OPUS_SAMPLE_RATES: [u32; 4] = [8000, 16000, 24000, 48000];
+
+/// Bitrates in bits per second accepted for Opus output.
+pub const OPUS_BITRATES: RangeInclusive<u32> = 6_000..=510_000;
+
+/// Duration of the audio in each Opus packet.
+pub const OPUS_FRAME_DURATION_MS: u32 = 20;
+
+/// Largest packet libopus is asked to produce, as recommended by its documentation.
+const MAX_PACKET_LEN: usize = 4000;
+
+/// Encodes frames of interleaved samples to Opus packets.
+pub struct OpusEncoder {
+    encoder: Encoder,
+    frame_samples: usize,
+    bitrate: u32,
+    complexity: u8,
+    pre_skip: u32,
+}
+
+impl OpusEncoder {
+    pub fn new(
+        sample_rate: u32,
+        channels: u8,
+        bitrate: u32,
+        complexity: u8,
+    ) -> audiopus::Result<Self> {
+        let opus_rate = match sample_rate {
+            8000 => SampleRate::Hz8000,
+            16000 => SampleRate::Hz16000,
+            24000 => SampleRate::Hz24000,
+            _ => SampleRate::Hz48000,
+        };
+        let opus_channels = if channels == 1 {
+            Channels::Mono
+        } else {
+            Channels::Stereo
+        };
+
+        let mut encoder = Encoder::new(opus_rate, opus_channels, Application::Audio)?;
+        encoder.set_bitrate(Bitrate::BitsPerSecond(bitrate as i32))?;
+        encoder.set_complexity(complexity)?;
+        let pre_skip = encoder.lookahead()?;
+
+        Ok(Self {
+            encoder,
+            frame_samples: (sample_rate * OPUS_FRAME_DURATION_MS / 1000) as usize
+                * usize::from(channels),
+            bitrate,
+            complexity,
+            pre_skip,
+        })
+    }
+
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate
+    }
+
+    pub fn complexity(&self) -> u8 {
+        self.complexity
+    }
+
+    /// Samples per channel a decoder should discard from the start of the stream.
+    pub fn pre_skip(&self) -> u32 {
+        self.pre_skip
+    }
+
+    /// Starts a new stream, the next packet doesn't depend on earlier ones.
+    pub fn reset(&mut self) {
+        let _ = self.encoder.reset_state();
+    }
+
+    /// Encodes exactly one frame of interleaved samples into a packet.
+    pub fn encode(&mut self, samples: &[f64]) -> audiopus::Result<Vec<u8>> {
+        debug_assert_eq!(samples.len(), self.frame_samples);
+        let input: Vec<f32> = samples.iter().map(|&sample| sample as f32).collect();
+
+        let mut packet = vec![0; MAX_PACKET_LEN];
+        let len = self.encoder.encode_float(&input, &mut packet)?;
+        packet.truncate(len);
+        Ok(packet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audiopus::coder::Decoder;
+    use std::f64::consts::PI;
+
+    #[test]
+    fn packets_decode_to_twenty_millisecond_frames() {
+        let mut encoder = OpusEncoder::new(48_000, 2, 96_000, 10).unwrap();
+        let mut decoder = Decoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+
+        for frame in 0..50 {
+            let samples: Vec<f64> = (0..960 * 2)
+                .map(|i| {
+                    let n = (frame * 960 + i / 2) as f64;
+                    (2.0 * PI * 440.0 * n / 48_000.0).sin() * 0.5
+                })
+                .collect();
+            let packet = encoder.encode(&samples).unwrap();
+            assert!(packet.len() <= 96_000 / 8 / 50 * 2);
+
+            let mut decoded = vec![0.0f32; 5760 * 2];
+            let decoded_len = decoder
+                .decode_float(
+                    Some((&packet).try_into().unwrap()),
+                    (&mut decoded).try_into().unwrap(),
+                    false,
+                )
+                .unwrap();
+            assert_eq!(decoded_len, 960);
+        }
+    }
+
+    #[test]
+    fn encodes_at_every_supported_rate_in_mono_and_stereo() {
+        for (sample_rate, opus_rate) in [
+            (8000, SampleRate::Hz8000),
+            (16000, SampleRate::Hz16000),
+            (24000, SampleRate::Hz24000),
+            (48000, SampleRate::Hz48000),
+        ] {
+            for (channels, opus_channels) in [(1, Channels::Mono), (2, Channels::Stereo)] {
+                let mut encoder = OpusEncoder::new(sample_rate, channels, 32_000, 5).unwrap();
+                let mut decoder = Decoder::new(opus_rate, opus_channels).unwrap();
+
+                let frame_len = (sample_rate / 50) as usize;
+                let samples = vec![0.25; frame_len * usize::from(channels)];
+                let packet = encoder.encode(&samples).unwrap();
+
+                let mut decoded = vec![0.0f32; 5760 * 2];
+                let decoded_len = decoder
+                    .decode_float(
+                        Some((&packet).try_into().unwrap()),
+                        (&mut decoded).try_into().unwrap(),
+                        false,
+                    )
+                    .unwrap();
+                assert_eq!(
+                    decoded_len, frame_len,
+                    "{sample_rate} Hz, {channels} channel(s)"
+                );
+            }
+        }
+    }
+}
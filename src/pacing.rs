@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+
+/// Keeps a sink's output in step with real time.
+///
+/// Playback time is derived from the number of sample frames written since the stream
+/// started, not from when the last chunk went out, so rounding and scheduling delays don't
+/// add up over a long session. Output runs `lead` ahead of real time: the first `lead` of
+/// audio is sent right away to fill the client's jitter buffer, after that audio is sent at
+/// the rate it plays.
+#[derive(Debug)]
+pub struct PlaybackClock {
+    sample_rate: u32,
+    lead: Duration,
+    /// When the first frame of the stream would start playing on the client.
+    started_at: Option<Instant>,
+    frames_written: u64,
+}
+
+impl PlaybackClock {
+    pub fn new(sample_rate: u32, lead: Duration) -> Self {
+        Self {
+            sample_rate,
+            lead,
+            started_at: None,
+            frames_written: 0,
+        }
+    }
+
+    /// Starts over with a new stream, the next write is prebuffered again.
+    pub fn reset(&mut self) {
+        self.started_at = None;
+        self.frames_written = 0;
+    }
+
+    pub fn lead(&self) -> Duration {
+        self.lead
+    }
+
+    /// Sample frames written since the stream started.
+    pub fn frames(&self) -> u64 {
+        self.frames_written
+    }
+
+    /// Playback position of the audio written so far.
+    pub fn position(&self) -> Duration {
+        frames_to_duration(self.frames_written, self.sample_rate)
+    }
+
+    /// How long to wait at `now` before writing more audio.
+    ///
+    /// A stream that fell behind real time by more than the lead, e.g. because decoding
+    /// stalled, has emptied the client's buffer, so the clock is moved forward to prebuffer
+    /// again instead of bursting out everything it is late by.
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        let started_at = *self.started_at.get_or_insert(now);
+        let due = started_at + self.position();
+
+        if now.saturating_duration_since(due) > self.lead {
+            self.started_at = Some(now - self.position());
+            return Duration::ZERO;
+        }
+
+        due.saturating_duration_since(now + self.lead)
+    }
+
+    /// Records `frames` sample frames (one sample per channel) as written.
+    pub fn advance(&mut self, frames: u64) {
+        self.frames_written += frames;
+    }
+}
+
+fn frames_to_duration(frames: u64, sample_rate: u32) -> Duration {
+    let secs = frames / u64::from(sample_rate);
+    let rem = frames % u64::from(sample_rate);
+    Duration::from_secs(secs) + Duration::from_nanos(rem * 1_000_000_000 / u64::from(sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44_100;
+
+    #[test]
+    fn prebuffers_lead_then_follows_real_time() {
+        let lead = Duration::from_millis(500);
+        let mut clock = PlaybackClock::new(RATE, lead);
+        let start = Instant::now();
+
+        // Chunks starting within the first 500ms go out without waiting
+        for _ in 0..=10 {
+            assert_eq!(clock.delay(start), Duration::ZERO);
+            clock.advance(2205);
+        }
+        assert_eq!(clock.delay(start), Duration::from_millis(50));
+
+        // Once caught up with the clock there is no wait again
+        assert_eq!(
+            clock.delay(start + Duration::from_millis(50)),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn does_not_drift_over_long_sessions() {
+        let mut clock = PlaybackClock::new(RATE, Duration::ZERO);
+        let start = Instant::now();
+
+        // An hour of 10ms chunks, waiting exactly as long as asked each time
+        let mut now = start;
+        for _ in 0..360_000 {
+            now += clock.delay(now);
+            clock.advance(441);
+        }
+
+        assert_eq!(clock.position(), Duration::from_secs(3600));
+        assert_eq!(now + clock.delay(now), start + Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn prebuffers_again_after_falling_behind() {
+        let lead = Duration::from_millis(200);
+        let mut clock = PlaybackClock::new(RATE, lead);
+        let start = Instant::now();
+        clock.delay(start);
+        clock.advance(RATE as u64); // 1s written
+
+        // Decoding stalled for 3s, the client ran dry
+        let late = start + Duration::from_secs(3);
+        assert_eq!(clock.delay(late), Duration::ZERO);
+        clock.advance(4410);
+        assert_eq!(clock.delay(late), Duration::ZERO);
+        clock.advance(4410);
+        assert_eq!(clock.delay(late), Duration::ZERO);
+        clock.advance(4410);
+        // 300ms written since the stall, 200ms of it is lead
+        assert_eq!(clock.delay(late), Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,270 @@
+use librespot::playback::player::PlayerEvent;
+use serde::Serialize;
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaybackStatus {
+    #[default]
+    Stopped,
+    Loading,
+    Playing,
+    Paused,
+}
+
+/// Snapshot of what a device is doing, rebuilt from the player events it emits.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PlaybackState {
+    pub device_name: String,
+    pub status: PlaybackStatus,
+    pub track_uri: Option<String>,
+    pub position_ms: u32,
+    pub duration_ms: Option<u32>,
+    pub volume: u16,
+    pub shuffle: bool,
+    pub repeat: bool,
+    pub repeat_track: bool,
+    /// When `position_ms` was last reported, used to extrapolate while playing.
+    #[serde(skip)]
+    position_updated_at: Option<Instant>,
+}
+
+impl PlaybackState {
+    pub fn new(device_name: String) -> Self {
+        Self {
+            device_name,
+            ..Default::default()
+        }
+    }
+
+    pub fn apply(&mut self, event: &PlayerEvent) {
+        match event {
+            PlayerEvent::Loading {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                let track_uri = track_id.to_string();
+                if self.track_uri.as_ref() != Some(&track_uri) {
+                    self.duration_ms = None;
+                }
+                self.track_uri = Some(track_uri);
+                self.status = PlaybackStatus::Loading;
+                self.set_position(*position_ms);
+            }
+            PlayerEvent::Playing {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                self.track_uri = Some(track_id.to_string());
+                self.status = PlaybackStatus::Playing;
+                self.set_position(*position_ms);
+            }
+            PlayerEvent::Paused {
+                track_id,
+                position_ms,
+                ..
+            } => {
+                self.track_uri = Some(track_id.to_string());
+                self.status = PlaybackStatus::Paused;
+                self.set_position(*position_ms);
+            }
+            PlayerEvent::Seeked { position_ms, .. }
+            | PlayerEvent::PositionCorrection { position_ms, .. } => {
+                self.set_position(*position_ms);
+            }
+            PlayerEvent::Stopped { .. } => {
+                self.status = PlaybackStatus::Stopped;
+                self.track_uri = None;
+                self.duration_ms = None;
+                self.set_position(0);
+            }
+            PlayerEvent::TrackChanged { audio_item } => {
+                self.track_uri = Some(audio_item.track_id.to_string());
+                self.duration_ms = Some(audio_item.duration_ms);
+            }
+            PlayerEvent::VolumeChanged { volume } => self.volume = *volume,
+            PlayerEvent::ShuffleChanged { shuffle } => self.shuffle = *shuffle,
+            PlayerEvent::RepeatChanged { context, track } => {
+                self.repeat = *context;
+                self.repeat_track = *track;
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns the state with the position advanced to now if the track is playing.
+    pub fn snapshot(&self) -> Self {
+        let mut snapshot = self.clone();
+
+        if let (PlaybackStatus::Playing, Some(updated_at)) = (self.status, self.position_updated_at)
+        {
+            let elapsed = u32::try_from(updated_at.elapsed().as_millis()).unwrap_or(u32::MAX);
+            let position = self.position_ms.saturating_add(elapsed);
+            snapshot.position_ms = match self.duration_ms {
+                Some(duration) => position.min(duration),
+                None => position,
+            };
+        }
+
+        snapshot
+    }
+
+    fn set_position(&mut self, position_ms: u32) {
+        self.position_ms = position_ms;
+        self.position_updated_at = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use librespot::core::spotify_id::SpotifyId;
+    use librespot::metadata::artist::ArtistsWithRole;
+    use librespot::metadata::audio::{AudioFiles, AudioItem, UniqueFields};
+
+    const TRACK_A: &str = "spotify:track:4uLU6hMCjMI75M1A2tKUQC";
+    const TRACK_B: &str = "spotify:track:0VjIjW4GlUZAMYd2vXMi3b";
+
+    fn track(uri: &str) -> SpotifyId {
+        SpotifyId::from_uri(uri).unwrap()
+    }
+
+    fn loading(uri: &str, position_ms: u32) -> PlayerEvent {
+        PlayerEvent::Loading {
+            play_request_id: 1,
+            track_id: track(uri),
+            position_ms,
+        }
+    }
+
+    fn playing(uri: &str, position_ms: u32) -> PlayerEvent {
+        PlayerEvent::Playing {
+            play_request_id: 1,
+            track_id: track(uri),
+            position_ms,
+        }
+    }
+
+    fn paused(uri: &str, position_ms: u32) -> PlayerEvent {
+        PlayerEvent::Paused {
+            play_request_id: 1,
+            track_id: track(uri),
+            position_ms,
+        }
+    }
+
+    fn track_changed(uri: &str, duration_ms: u32) -> PlayerEvent {
+        PlayerEvent::TrackChanged {
+            audio_item: Box::new(AudioItem {
+                track_id: track(uri),
+                uri: uri.to_string(),
+                files: AudioFiles::default(),
+                name: "Track".to_string(),
+                covers: Vec::new(),
+                language: Vec::new(),
+                duration_ms,
+                is_explicit: false,
+                availability: Ok(()),
+                alternatives: None,
+                unique_fields: UniqueFields::Track {
+                    artists: ArtistsWithRole::default(),
+                    album: "Album".to_string(),
+                    album_artists: Vec::new(),
+                    popularity: 0,
+                    number: 1,
+                    disc_number: 1,
+                },
+            }),
+        }
+    }
+
+    fn state(events: &[PlayerEvent]) -> PlaybackState {
+        let mut state = PlaybackState::new("Device".to_string());
+        for event in events {
+            state.apply(event);
+        }
+        state
+    }
+
+    #[test]
+    fn load_then_play() {
+        let mut state = state(&[loading(TRACK_A, 0)]);
+        assert_eq!(state.status, PlaybackStatus::Loading);
+        assert_eq!(state.track_uri, Some(track(TRACK_A).to_string()));
+
+        state.apply(&playing(TRACK_A, 1_500));
+        assert_eq!(state.status, PlaybackStatus::Playing);
+        assert_eq!(state.position_ms, 1_500);
+        assert!(state.snapshot().position_ms >= 1_500);
+    }
+
+    #[test]
+    fn pause_keeps_the_position() {
+        let state = state(&[playing(TRACK_A, 0), paused(TRACK_A, 42_000)]);
+        assert_eq!(state.status, PlaybackStatus::Paused);
+        assert_eq!(state.snapshot().position_ms, 42_000);
+    }
+
+    #[test]
+    fn seek_moves_the_position_without_changing_status() {
+        let state = state(&[
+            paused(TRACK_A, 1_000),
+            PlayerEvent::Seeked {
+                play_request_id: 1,
+                track_id: track(TRACK_A),
+                position_ms: 90_000,
+            },
+        ]);
+        assert_eq!(state.status, PlaybackStatus::Paused);
+        assert_eq!(state.snapshot().position_ms, 90_000);
+    }
+
+    #[test]
+    fn track_change_sets_the_track_and_duration() {
+        let mut state = state(&[
+            loading(TRACK_A, 0),
+            track_changed(TRACK_A, 200_000),
+            playing(TRACK_A, 0),
+        ]);
+        assert_eq!(state.track_uri, Some(track(TRACK_A).to_string()));
+        assert_eq!(state.duration_ms, Some(200_000));
+
+        // Playing the same track again keeps its duration
+        state.apply(&loading(TRACK_A, 0));
+        assert_eq!(state.duration_ms, Some(200_000));
+
+        // Unknown until the next track's TrackChanged
+        state.apply(&loading(TRACK_B, 0));
+        assert_eq!(state.track_uri, Some(track(TRACK_B).to_string()));
+        assert_eq!(state.duration_ms, None);
+
+        state.apply(&track_changed(TRACK_B, 180_000));
+        state.apply(&playing(TRACK_B, 0));
+        assert_eq!(state.status, PlaybackStatus::Playing);
+        assert_eq!(state.duration_ms, Some(180_000));
+    }
+
+    #[test]
+    fn playing_position_stops_at_the_end_of_the_track() {
+        let mut state = state(&[track_changed(TRACK_A, 200_000), playing(TRACK_A, 199_990)]);
+        state.position_updated_at = Some(Instant::now() - std::time::Duration::from_secs(1));
+
+        assert_eq!(state.snapshot().position_ms, 200_000);
+    }
+
+    #[test]
+    fn stop_clears_the_track() {
+        let mut state = state(&[track_changed(TRACK_A, 200_000), playing(TRACK_A, 30_000)]);
+        state.apply(&PlayerEvent::Stopped {
+            play_request_id: 1,
+            track_id: track(TRACK_A),
+        });
+
+        assert_eq!(state.status, PlaybackStatus::Stopped);
+        assert_eq!(state.track_uri, None);
+        assert_eq!(state.duration_ms, None);
+        assert_eq!(state.snapshot().position_ms, 0);
+    }
+}
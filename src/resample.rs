@@ -0,0 +1,284 @@
+use std::f64::consts::PI;
+
+/// Zero crossings of the sinc on each side of a tap, more is a steeper filter.
+const ZERO_CROSSINGS: usize = 16;
+
+/// Cutoff relative to the lower of the two Nyquist frequencies, the rest is transition band.
+const ROLLOFF: f64 = 0.95;
+
+/// Kaiser window shape, about 80 dB of stopband attenuation.
+const KAISER_BETA: f64 = 8.0;
+
+/// How a sink's stereo input is mixed to the channels it sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelMixer {
+    Stereo,
+    /// Average of left and right.
+    Mono,
+}
+
+impl ChannelMixer {
+    /// Mixer from interleaved stereo to `channels`, if supported.
+    pub fn for_channels(channels: u8) -> Option<Self> {
+        match channels {
+            1 => Some(ChannelMixer::Mono),
+            2 => Some(ChannelMixer::Stereo),
+            _ => None,
+        }
+    }
+
+    /// Mixes interleaved stereo samples into `out`.
+    pub fn mix(self, stereo: &[f64], out: &mut Vec<f64>) {
+        match self {
+            ChannelMixer::Stereo => out.extend_from_slice(stereo),
+            ChannelMixer::Mono => out.extend(
+                stereo
+                    .chunks_exact(2)
+                    .map(|frame| (frame[0] + frame[1]) * 0.5),
+            ),
+        }
+    }
+}
+
+/// Streaming windowed-sinc sample rate converter for interleaved audio.
+///
+/// The rate ratio is reduced to `up / down` and a polyphase bank of Kaiser windowed sinc
+/// filters is computed up front, one per output phase, so converting is a dot product per
+/// output sample. Input can be fed in chunks of any size, the output is the same as if it
+/// was converted in one go. Output sample `n` is the input interpolated at time `n / out_rate`,
+/// so the filter adds no delay, but the last `taps / 2` input frames are held back until
+/// the input after them arrives.
+pub struct Resampler {
+    channels: usize,
+    up: usize,
+    down: usize,
+    /// Input frames on each side of an output sample.
+    half_taps: usize,
+    /// `up` phases of `2 * half_taps` coefficients.
+    coefficients: Vec<f64>,
+    /// Interleaved input frames not fully used yet.
+    history: Vec<f64>,
+    /// Input frame in `history` the next output sample is interpolated after.
+    index: usize,
+    /// Position of the next output sample between `index` and `index + 1`, in `1 / up` steps.
+    phase: usize,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> Self {
+        let divisor = gcd(from_rate, to_rate);
+        let up = (to_rate / divisor) as usize;
+        let down = (from_rate / divisor) as usize;
+
+        // Downsampling moves the cutoff below the output's Nyquist frequency and widens the
+        // filter accordingly
+        let cutoff = ROLLOFF * (up as f64 / down as f64).min(1.0);
+        let half_taps = (ZERO_CROSSINGS as f64 / cutoff).ceil() as usize;
+        let taps = 2 * half_taps;
+
+        let mut coefficients = Vec::with_capacity(up * taps);
+        for phase in 0..up {
+            let start = coefficients.len();
+            for tap in 0..taps {
+                // Distance from the output sample back to the input frame of this tap
+                let x = half_taps as f64 - 1.0 - tap as f64 + phase as f64 / up as f64;
+                coefficients.push(cutoff * sinc(cutoff * x) * kaiser(x / half_taps as f64));
+            }
+            // Unity gain at DC for every phase, otherwise the phases ripple against each other
+            let sum: f64 = coefficients[start..].iter().sum();
+            for coefficient in &mut coefficients[start..] {
+                *coefficient /= sum;
+            }
+        }
+
+        let mut resampler = Self {
+            channels,
+            up,
+            down,
+            half_taps,
+            coefficients,
+            history: Vec::new(),
+            index: 0,
+            phase: 0,
+        };
+        resampler.reset();
+        resampler
+    }
+
+    /// Forgets all buffered input, for the start of a new stream.
+    pub fn reset(&mut self) {
+        // Silence before the stream, so the first output sample lines up with the first input
+        self.history.clear();
+        self.history.resize(self.half_taps * self.channels, 0.0);
+        self.index = self.half_taps;
+        self.phase = 0;
+    }
+
+    /// Converts interleaved `input` and appends the output samples available so far to `out`.
+    pub fn process(&mut self, input: &[f64], out: &mut Vec<f64>) {
+        self.history.extend_from_slice(input);
+        let frames = self.history.len() / self.channels;
+        let taps = 2 * self.half_taps;
+
+        while self.index + self.half_taps < frames {
+            let first = (self.index + 1 - self.half_taps) * self.channels;
+            let coefficients = &self.coefficients[self.phase * taps..(self.phase + 1) * taps];
+            for channel in 0..self.channels {
+                let sample = coefficients
+                    .iter()
+                    .enumerate()
+                    .map(|(tap, c)| c * self.history[first + tap * self.channels + channel])
+                    .sum();
+                out.push(sample);
+            }
+
+            self.phase += self.down;
+            self.index += self.phase / self.up;
+            self.phase %= self.up;
+        }
+
+        // Keep only the frames the next output sample still needs
+        let consumed = (self.index + 1 - self.half_taps).min(frames);
+        self.history.drain(..consumed * self.channels);
+        self.index -= consumed;
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Kaiser window at `x` in -1..1.
+fn kaiser(x: f64) -> f64 {
+    bessel_i0(KAISER_BETA * (1.0 - x * x).max(0.0).sqrt()) / bessel_i0(KAISER_BETA)
+}
+
+/// Zeroth order modified Bessel function of the first kind, by its power series.
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)).powi(2);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, rate: u32, frames: usize) -> Vec<f64> {
+        (0..frames)
+            .map(|n| (2.0 * PI * frequency * n as f64 / rate as f64).sin() * 0.8)
+            .collect()
+    }
+
+    fn rms(samples: &[f64]) -> f64 {
+        (samples.iter().map(|s| s * s).sum::<f64>() / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn matches_reference_sine_after_upsampling() {
+        let input = sine(1000.0, 44_100, 44_100);
+        let mut output = Vec::new();
+        Resampler::new(44_100, 48_000, 1).process(&input, &mut output);
+
+        // Everything but the last few input frames, which are still held back
+        assert!(output.len() > 47_900 && output.len() <= 48_000);
+        let reference = sine(1000.0, 48_000, output.len());
+        let max_error = output[100..]
+            .iter()
+            .zip(&reference[100..])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max);
+        assert!(max_error < 1e-3, "max error {max_error}");
+    }
+
+    #[test]
+    fn matches_reference_sine_after_downsampling() {
+        let input = sine(440.0, 44_100, 44_100);
+        let mut output = Vec::new();
+        Resampler::new(44_100, 16_000, 1).process(&input, &mut output);
+
+        let reference = sine(440.0, 16_000, output.len());
+        let max_error = output[100..]
+            .iter()
+            .zip(&reference[100..])
+            .map(|(a, b)| (a - b).abs())
+            .fold(0.0, f64::max);
+        assert!(max_error < 1e-3, "max error {max_error}");
+    }
+
+    #[test]
+    fn removes_frequencies_above_the_new_nyquist() {
+        // 10 kHz can't be represented at 16 kHz and would alias to 6 kHz
+        let input = sine(10_000.0, 44_100, 44_100);
+        let mut output = Vec::new();
+        Resampler::new(44_100, 16_000, 1).process(&input, &mut output);
+
+        let level = rms(&output[200..]) / rms(&input);
+        assert!(level < 1e-3, "{:.1} dB", 20.0 * level.log10());
+    }
+
+    #[test]
+    fn chunked_input_gives_the_same_output() {
+        let left = sine(1000.0, 44_100, 10_000);
+        let right = sine(3000.0, 44_100, 10_000);
+        let input: Vec<f64> = left
+            .iter()
+            .zip(&right)
+            .flat_map(|(l, r)| [*l, *r])
+            .collect();
+
+        let mut whole = Vec::new();
+        Resampler::new(44_100, 48_000, 2).process(&input, &mut whole);
+
+        let mut resampler = Resampler::new(44_100, 48_000, 2);
+        let mut chunked = Vec::new();
+        for chunk in input.chunks(2 * 331) {
+            resampler.process(chunk, &mut chunked);
+        }
+
+        assert_eq!(whole.len(), chunked.len());
+        for (a, b) in whole.iter().zip(&chunked) {
+            assert!((a - b).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn keeps_channels_apart() {
+        let left = sine(1000.0, 44_100, 10_000);
+        let input: Vec<f64> = left.iter().flat_map(|l| [*l, 0.0]).collect();
+        let mut output = Vec::new();
+        Resampler::new(44_100, 48_000, 2).process(&input, &mut output);
+
+        let right: Vec<f64> = output.iter().skip(1).step_by(2).copied().collect();
+        assert!(right.iter().all(|s| *s == 0.0));
+    }
+
+    #[test]
+    fn mono_mix_averages_left_and_right() {
+        let stereo = [0.5, 0.5, 1.0, -1.0, 0.25, 0.75];
+        let mut mono = Vec::new();
+        ChannelMixer::Mono.mix(&stereo, &mut mono);
+        assert_eq!(mono, [0.5, 0.0, 0.5]);
+
+        let mut passthrough = Vec::new();
+        ChannelMixer::Stereo.mix(&stereo, &mut passthrough);
+        assert_eq!(passthrough, stereo);
+    }
+}
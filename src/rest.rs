@@ -0,0 +1,587 @@
+use crate::auth::{authenticated, ClientAuth, ClientIdentity};
+use crate::command_manager::CommandManager;
+use crate::commands::{
+    Command, CommandMessage, CommandResponse, CommandSpec, HttpMethod, ParamKind, DEVICE_COMMANDS,
+};
+use crate::device_registry::DeviceRegistry;
+use crate::metrics::{metrics, INVALID_COMMAND};
+use futures::{future, stream, StreamExt};
+use log::info;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use tokio_stream::wrappers::BroadcastStream;
+use warp::http::{Method, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::reply::{Json, WithStatus};
+use warp::sse::Event;
+use warp::{Filter, Rejection, Reply};
+
+/// Largest request body accepted for a command.
+const MAX_BODY_LEN: u64 = 64 * 1024;
+
+/// HTTP routes for clients that only need to fire a command without holding a socket open.
+///
+/// - `GET /devices` lists the devices of the calling client
+/// - `GET /devices/{device_id}/events` streams the device's events as server-sent events
+/// - `GET|POST /devices/{device_id}/{command}` runs one of [`DEVICE_COMMANDS`]
+/// - `GET /openapi.json` describes the above
+///
+/// Devices belong to the client that created them, other clients get a `404` as if the
+/// device didn't exist.
+pub fn routes(
+    registry: DeviceRegistry,
+    command_manager: CommandManager,
+    auth: ClientAuth,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let list_registry = registry.clone();
+    let list_devices = warp::path!("devices")
+        .and(warp::get())
+        .and(authenticated(auth.clone()))
+        .map(move |identity: ClientIdentity| list_devices(&list_registry, &identity));
+
+    let events_registry = registry.clone();
+    let device_events = warp::path!("devices" / String / "events")
+        .and(warp::get())
+        .and(authenticated(auth.clone()))
+        .and(warp::header::optional::<u64>("last-event-id"))
+        .map(
+            move |device_id: String, identity: ClientIdentity, last_event_id: Option<u64>| {
+                device_events(&events_registry, &identity, &device_id, last_event_id)
+            },
+        );
+
+    let device_command = warp::path!("devices" / String / String)
+        .and(warp::method())
+        .and(authenticated(auth))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(request_body())
+        .and_then(
+            move |device_id: String,
+                  path: String,
+                  method: Method,
+                  identity: ClientIdentity,
+                  query: HashMap<String, String>,
+                  body: Bytes| {
+                let registry = registry.clone();
+                let command_manager = command_manager.clone();
+                async move {
+                    let reply = run_command(
+                        &registry,
+                        &command_manager,
+                        &identity,
+                        &device_id,
+                        &path,
+                        &method,
+                        &query,
+                        &body,
+                    )
+                    .await;
+                    Ok::<_, Rejection>(reply)
+                }
+            },
+        );
+
+    let openapi = warp::path!("openapi.json")
+        .and(warp::get())
+        .map(|| warp::reply::json(&openapi_document()));
+
+    list_devices
+        .or(device_events)
+        .or(device_command)
+        .or(openapi)
+}
+
+/// Body of a command request, at most [`MAX_BODY_LEN`] bytes. Requests without a body, such
+/// as `curl -X POST` without data, have no `Content-Length` and get an empty one.
+fn request_body() -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    let no_body = warp::header::optional::<String>("content-length")
+        .and(warp::header::optional::<String>("transfer-encoding"))
+        .and_then(
+            |length: Option<String>, encoding: Option<String>| async move {
+                if length.is_none() && encoding.is_none() {
+                    Ok(Bytes::new())
+                } else {
+                    Err(warp::reject())
+                }
+            },
+        );
+
+    no_body
+        .or(warp::body::content_length_limit(MAX_BODY_LEN).and(warp::body::bytes()))
+        .unify()
+}
+
+fn list_devices(registry: &DeviceRegistry, identity: &ClientIdentity) -> Json {
+    let devices: Vec<Value> = registry
+        .devices_owned_by(&identity.client_id)
+        .into_iter()
+        .map(|device| {
+            json!({
+                "device_id": device.device_id,
+                "device_name": device.device_name,
+                "connected": device.is_connected(),
+            })
+        })
+        .collect();
+
+    warp::reply::json(&CommandResponse::success(
+        format!("{} device(s)", devices.len()),
+        Some(json!({ "devices": devices })),
+    ))
+}
+
+/// Streams the device's control messages, replaying those after `last_event_id` from the
+/// backlog first. The stream ends when the device is removed, or when the observer falls too
+/// far behind, in which case `EventSource` reconnects and resumes from the backlog.
+fn device_events(
+    registry: &DeviceRegistry,
+    identity: &ClientIdentity,
+    device_id: &str,
+    last_event_id: Option<u64>,
+) -> Box<dyn Reply> {
+    let Some(entry) = registry.owned_device(device_id, &identity.client_id) else {
+        return Box::new(reply(
+            StatusCode::NOT_FOUND,
+            CommandResponse::error("Device not found"),
+        ));
+    };
+
+    let (missed, receiver) = entry.events.subscribe(last_event_id);
+    let live = BroadcastStream::new(receiver).take_while(|result| future::ready(result.is_ok()));
+    let events = stream::iter(missed)
+        .chain(live.filter_map(|result| future::ready(result.ok())))
+        .map(|event| {
+            Ok::<_, Infallible>(
+                Event::default()
+                    .id(event.id.to_string())
+                    .event(event.event_type)
+                    .data(&*event.json),
+            )
+        });
+
+    Box::new(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_command(
+    registry: &DeviceRegistry,
+    command_manager: &CommandManager,
+    identity: &ClientIdentity,
+    device_id: &str,
+    path: &str,
+    method: &Method,
+    query: &HashMap<String, String>,
+    body: &[u8],
+) -> WithStatus<Json> {
+    let Some(spec) = DEVICE_COMMANDS.iter().find(|spec| spec.path == path) else {
+        return reply(
+            StatusCode::NOT_FOUND,
+            CommandResponse::error(format!("Unknown command: {path}")),
+        );
+    };
+
+    let allowed = match spec.method {
+        HttpMethod::Get => Method::GET,
+        HttpMethod::Post => Method::POST,
+    };
+    if *method != allowed {
+        return reply(
+            StatusCode::METHOD_NOT_ALLOWED,
+            CommandResponse::error(format!("Use {allowed} for {path}")),
+        );
+    }
+
+    let params = match request_params(spec, query, body) {
+        Ok(params) => params,
+        Err(e) => {
+            metrics().command_executed(INVALID_COMMAND, false);
+            return reply(StatusCode::BAD_REQUEST, CommandResponse::error(e));
+        }
+    };
+
+    let message = CommandMessage {
+        request_id: None,
+        device_id: Some(device_id.to_string()),
+        command_type: spec.command_type.to_string(),
+        params: Value::Object(params),
+    };
+    let command = match Command::from_message(message) {
+        Ok((_, command)) => command,
+        Err(e) => {
+            metrics().command_executed(INVALID_COMMAND, false);
+            return reply(
+                StatusCode::BAD_REQUEST,
+                CommandResponse::error(format!("Invalid command: {e}")),
+            );
+        }
+    };
+
+    let Some(entry) = registry.owned_device(device_id, &identity.client_id) else {
+        return reply(
+            StatusCode::NOT_FOUND,
+            CommandResponse::error("Device not found"),
+        );
+    };
+
+    info!(
+        "Client {} running {} on device {device_id} over HTTP",
+        identity.client_id, spec.command_type
+    );
+
+    let mut state = entry.session.lock().await;
+    // The device may have been removed while we waited for the lock
+    let Some(spotify) = state.devices.get_mut(device_id) else {
+        return reply(
+            StatusCode::NOT_FOUND,
+            CommandResponse::error("Device not found"),
+        );
+    };
+
+    let response = command_manager.execute(command, spotify);
+    // Lookups can take a while, don't hold up the session meanwhile
+    drop(state);
+    let response = response.await;
+    let status = if response.success {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    reply(status, response)
+}
+
+/// Builds command params from the query string, typed according to `spec`, and for POST
+/// requests from a JSON object body, which takes precedence.
+fn request_params(
+    spec: &CommandSpec,
+    query: &HashMap<String, String>,
+    body: &[u8],
+) -> Result<Map<String, Value>, String> {
+    let mut params = Map::new();
+
+    for param in spec.params {
+        let Some(raw) = query.get(param.name) else {
+            continue;
+        };
+        let value =
+            match param.kind {
+                ParamKind::String => Value::String(raw.clone()),
+                ParamKind::Boolean => Value::Bool(raw.parse().map_err(|_| {
+                    format!("Invalid {} parameter, expected a boolean", param.name)
+                })?),
+                ParamKind::Integer => Value::from(raw.parse::<u64>().map_err(|_| {
+                    format!("Invalid {} parameter, expected an integer", param.name)
+                })?),
+            };
+        params.insert(param.name.to_string(), value);
+    }
+
+    if !body.iter().all(u8::is_ascii_whitespace) {
+        match serde_json::from_slice(body) {
+            Ok(Value::Object(body)) => params.extend(body),
+            Ok(_) => return Err("Request body must be a JSON object".to_string()),
+            Err(e) => return Err(format!("Invalid JSON format: {e}")),
+        }
+    }
+
+    Ok(params)
+}
+
+fn reply(status: StatusCode, response: CommandResponse) -> WithStatus<Json> {
+    warp::reply::with_status(warp::reply::json(&response), status)
+}
+
+/// OpenAPI 3 description of [`routes`], generated from [`DEVICE_COMMANDS`].
+pub fn openapi_document() -> Value {
+    let responses = json!({
+        "200": { "$ref": "#/components/responses/Success" },
+        "400": { "$ref": "#/components/responses/Failure" },
+        "401": { "$ref": "#/components/responses/Failure" },
+        "404": { "$ref": "#/components/responses/Failure" },
+    });
+
+    let mut paths = Map::new();
+    paths.insert(
+        "/devices".to_string(),
+        json!({
+            "get": {
+                "operationId": "ListDevices",
+                "summary": "List the devices of the calling client",
+                "responses": responses,
+            }
+        }),
+    );
+    paths.insert(
+        "/devices/{device_id}/events".to_string(),
+        json!({
+            "get": {
+                "operationId": "DeviceEvents",
+                "summary": "Stream the device's events as server-sent events",
+                "parameters": [
+                    { "$ref": "#/components/parameters/DeviceId" },
+                    {
+                        "name": "Last-Event-ID",
+                        "in": "header",
+                        "required": false,
+                        "description": "Replay the backlogged events after this id",
+                        "schema": { "type": "integer" },
+                    },
+                ],
+                "responses": {
+                    "200": {
+                        "description": "Event stream, each event's data is a WebSocket message",
+                        "content": { "text/event-stream": { "schema": { "type": "string" } } },
+                    },
+                    "401": { "$ref": "#/components/responses/Failure" },
+                    "404": { "$ref": "#/components/responses/Failure" },
+                },
+            }
+        }),
+    );
+
+    for spec in DEVICE_COMMANDS {
+        let mut operation = json!({
+            "operationId": spec.command_type,
+            "summary": spec.description,
+            "parameters": [{ "$ref": "#/components/parameters/DeviceId" }],
+            "responses": responses,
+        });
+
+        let method = match spec.method {
+            HttpMethod::Get => {
+                let parameters = operation["parameters"].as_array_mut().unwrap();
+                for param in spec.params {
+                    parameters.push(json!({
+                        "name": param.name,
+                        "in": "query",
+                        "required": param.required,
+                        "description": param.description,
+                        "schema": { "type": param.kind.json_type() },
+                    }));
+                }
+                "get"
+            }
+            HttpMethod::Post => {
+                if !spec.params.is_empty() {
+                    let properties: Map<String, Value> = spec
+                        .params
+                        .iter()
+                        .map(|param| {
+                            (
+                                param.name.to_string(),
+                                json!({
+                                    "type": param.kind.json_type(),
+                                    "description": param.description,
+                                }),
+                            )
+                        })
+                        .collect();
+                    let required: Vec<&str> = spec
+                        .params
+                        .iter()
+                        .filter(|param| param.required)
+                        .map(|param| param.name)
+                        .collect();
+
+                    operation["requestBody"] = json!({
+                        "required": !required.is_empty(),
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "object",
+                                    "properties": properties,
+                                    "required": required,
+                                }
+                            }
+                        }
+                    });
+                }
+                "post"
+            }
+        };
+
+        paths.insert(
+            format!("/devices/{{device_id}}/{}", spec.path),
+            json!({ method: operation }),
+        );
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Blockyspot",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "security": [{ "bearerAuth": [] }],
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearerAuth": { "type": "http", "scheme": "bearer" },
+            },
+            "parameters": {
+                "DeviceId": {
+                    "name": "device_id",
+                    "in": "path",
+                    "required": true,
+                    "schema": { "type": "string" },
+                },
+            },
+            "schemas": {
+                "CommandResponse": {
+                    "type": "object",
+                    "properties": {
+                        "type": { "type": "string", "enum": ["response"] },
+                        "success": { "type": "boolean" },
+                        "message": { "type": "string" },
+                        "data": { "nullable": true },
+                    },
+                    "required": ["type", "success", "message"],
+                },
+            },
+            "responses": {
+                "Success": {
+                    "description": "Command ran",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/CommandResponse" }
+                        }
+                    },
+                },
+                "Failure": {
+                    "description": "Command failed, see `message`",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/CommandResponse" }
+                        }
+                    },
+                },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ApiKeyAuthenticator;
+    use crate::client_channel::{outgoing_queue, AudioOverflowPolicy};
+    use crate::device_events::DeviceEvents;
+    use crate::spotify::SpotifyClient;
+    use crate::ws_sink::AudioFraming;
+    use std::time::Duration;
+
+    const DEVICE_ID: &str = "device-a";
+
+    /// Registry with one device created by the client authenticating with `key-a`.
+    async fn registry() -> DeviceRegistry {
+        let registry = DeviceRegistry::new(Duration::from_secs(30));
+        let (sender, _receiver) = outgoing_queue(8, AudioOverflowPolicy::default());
+        let attached = registry
+            .attach(None, "api-key:0", sender, AudioFraming::Json)
+            .await;
+
+        let mut state = attached.session.lock().await;
+        let events = DeviceEvents::new(state.channel.clone());
+        registry.register_device(
+            &attached.session,
+            state.id,
+            &events,
+            DEVICE_ID,
+            "Device A",
+            "api-key:0",
+        );
+        state
+            .devices
+            .insert(DEVICE_ID.to_string(), SpotifyClient::new());
+        drop(state);
+
+        registry
+    }
+
+    async fn routes_for_test() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        let auth = ClientAuth::new(vec![Box::new(ApiKeyAuthenticator::new(vec![
+            "key-a".to_string(),
+            "key-b".to_string(),
+        ]))]);
+        routes(registry().await, CommandManager::new(), auth)
+    }
+
+    fn body_json(body: &[u8]) -> Value {
+        serde_json::from_slice(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn lists_only_the_callers_devices() {
+        let routes = routes_for_test().await;
+
+        let owner = warp::test::request()
+            .path("/devices")
+            .header("authorization", "Bearer key-a")
+            .reply(&routes)
+            .await;
+        assert_eq!(owner.status(), StatusCode::OK);
+        let devices = &body_json(owner.body())["data"]["devices"];
+        assert_eq!(devices.as_array().unwrap().len(), 1);
+        assert_eq!(devices[0]["device_id"], DEVICE_ID);
+
+        let other = warp::test::request()
+            .path("/devices")
+            .header("authorization", "Bearer key-b")
+            .reply(&routes)
+            .await;
+        assert_eq!(other.status(), StatusCode::OK);
+        assert_eq!(body_json(other.body())["data"]["devices"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn other_clients_cannot_run_commands_on_a_device() {
+        let routes = routes_for_test().await;
+
+        let owner = warp::test::request()
+            .path("/devices/device-a/state")
+            .header("authorization", "Bearer key-a")
+            .reply(&routes)
+            .await;
+        assert_eq!(owner.status(), StatusCode::OK);
+
+        for (method, path) in [
+            ("GET", "/devices/device-a/state"),
+            ("POST", "/devices/device-a/pause"),
+        ] {
+            let other = warp::test::request()
+                .method(method)
+                .path(path)
+                .header("authorization", "Bearer key-b")
+                .reply(&routes)
+                .await;
+            assert_eq!(other.status(), StatusCode::NOT_FOUND, "{method} {path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn other_clients_cannot_watch_device_events() {
+        let routes = routes_for_test().await;
+
+        let other = warp::test::request()
+            .path("/devices/device-a/events")
+            .header("authorization", "Bearer key-b")
+            .header("last-event-id", "0")
+            .reply(&routes)
+            .await;
+        assert_eq!(other.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_bodies() {
+        let routes = routes_for_test().await;
+
+        let response = warp::test::request()
+            .method("POST")
+            .path("/devices/device-a/set_volume")
+            .header("authorization", "Bearer key-a")
+            .body(vec![b' '; MAX_BODY_LEN as usize + 1])
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
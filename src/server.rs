@@ -1,35 +1,82 @@
+use crate::auth::{allowed_origin, authenticated, handle_rejection, ClientAuth, ClientIdentity};
+use crate::client_channel::{outgoing_queue, AudioOverflowPolicy, ClientChannel, OutgoingSender};
 use crate::command_manager::CommandManager;
 use crate::commands::{Command, CommandMessage, CommandResponse};
-use crate::spotify::SpotifyClient;
-use futures::{FutureExt, StreamExt};
-use log::{error, info};
-use std::collections::HashMap;
-
+use crate::device_registry::{AttachedSession, ClientSession, DeviceRegistry};
+use crate::metrics::{metrics, INVALID_COMMAND};
+use crate::rest;
+use crate::spotify::{RemovalReason, SpotifyClient};
+use crate::tls::{tls_incoming, ReloadableTlsAcceptor, TlsConfig};
+use crate::ws_sink::AudioFraming;
+use anyhow::Context;
+use futures::{SinkExt, Stream, StreamExt};
+use log::{error, info, warn};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{watch, Mutex};
+use tokio::time::Instant;
 use uuid::Uuid;
+use warp::http::StatusCode;
 use warp::ws::{Message, WebSocket};
 use warp::Filter;
 
-const PROTOCOL_VERSION: &str = "0.1.1";
-
-pub type WsResult<T> = std::result::Result<T, warp::Error>;
+const PROTOCOL_VERSION: &str = "0.5.0";
 
 #[derive(Debug, serde::Serialize)]
 struct ConnectionResponse {
     status: String,
     protocol_version: String,
+    audio_framing: AudioFraming,
+    audio_overflow: AudioOverflowPolicy,
+    resume_token: String,
+    resumed: bool,
+    devices: Vec<String>,
+}
+
+/// Options a client picks in the query string of the WebSocket upgrade request,
+/// e.g. `ws://localhost:8888/ws?audio_framing=binary`.
+#[derive(Debug, Default, serde::Deserialize)]
+struct ConnectionParams {
+    #[serde(default)]
+    audio_framing: AudioFraming,
+    /// Overrides the server's `--audio-overflow` policy for this connection.
+    #[serde(default)]
+    audio_overflow: Option<AudioOverflowPolicy>,
+    /// Token from a previous connection's response to reattach to its devices.
+    #[serde(default)]
+    resume_token: Option<String>,
 }
 
-struct ConnectionState {
-    devices: HashMap<String, SpotifyClient>,
+pub struct ServerConfig {
+    /// How long devices of a disconnected client are kept alive for it to reconnect.
+    pub resume_grace_period: Duration,
+    pub auth: ClientAuth,
+    /// Browser origins allowed to connect, any origin if empty.
+    pub allowed_origins: Vec<String>,
+    /// How long shutdown keeps serving after `/readyz` starts failing, for load balancers to
+    /// notice and stop sending new clients.
+    pub readiness_drain: Duration,
+    /// How long shutdown waits for devices to leave Spotify Connect and clients to disconnect.
+    pub shutdown_timeout: Duration,
+    /// Audio frames a connection may have queued before `audio_overflow` applies.
+    pub audio_queue_len: usize,
+    pub audio_overflow: AudioOverflowPolicy,
 }
 
-impl ConnectionState {
-    fn new() -> Self {
+impl Default for ServerConfig {
+    fn default() -> Self {
         Self {
-            devices: HashMap::new(),
+            resume_grace_period: Duration::from_secs(30),
+            auth: ClientAuth::default(),
+            allowed_origins: Vec::new(),
+            readiness_drain: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(10),
+            audio_queue_len: 50,
+            audio_overflow: AudioOverflowPolicy::default(),
         }
     }
 }
@@ -37,52 +84,291 @@ impl ConnectionState {
 #[derive(Clone)]
 pub struct SpotifyServer {
     command_manager: CommandManager,
+    registry: DeviceRegistry,
+    auth: ClientAuth,
+    allowed_origins: Arc<Vec<String>>,
+    readiness_drain: Duration,
+    shutdown_timeout: Duration,
+    audio_queue_len: usize,
+    audio_overflow: AudioOverflowPolicy,
+    /// Whether the server is accepting connections, reported by `/readyz`.
+    ready: Arc<AtomicBool>,
 }
 
 impl SpotifyServer {
-    pub fn new() -> Self {
+    pub fn new(config: ServerConfig) -> Self {
         Self {
             command_manager: CommandManager::new(),
+            registry: DeviceRegistry::new(config.resume_grace_period),
+            auth: config.auth,
+            allowed_origins: Arc::new(config.allowed_origins),
+            readiness_drain: config.readiness_drain,
+            shutdown_timeout: config.shutdown_timeout,
+            audio_queue_len: config.audio_queue_len,
+            audio_overflow: config.audio_overflow,
+            ready: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    pub async fn start(self, port: u16) {
+    /// Serves on `addrs` until `shutdown` resolves, then reports not ready for the readiness
+    /// drain period, shuts every device down and waits up to the configured shutdown timeout
+    /// for clients to be told.
+    pub async fn start(
+        self,
+        addrs: Vec<SocketAddr>,
+        tls: Option<TlsConfig>,
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        if !self.auth.is_enabled() {
+            warn!(
+                "No client authentication configured, anyone who can reach the server can use it"
+            );
+        }
+
         let server = self.clone();
         let ws_route = warp::path("ws")
+            .and(allowed_origin(self.allowed_origins.clone()))
             .and(warp::ws())
-            .map(move |ws: warp::ws::Ws| {
-                let server = server.clone();
-                ws.on_upgrade(move |socket| server.handle_client_connection(socket))
+            .and(authenticated(self.auth.clone()))
+            .and(warp::query::<ConnectionParams>())
+            .map(
+                move |ws: warp::ws::Ws, identity: ClientIdentity, params: ConnectionParams| {
+                    let server = server.clone();
+                    ws.on_upgrade(move |socket| {
+                        server.handle_client_connection(socket, identity, params)
+                    })
+                },
+            );
+
+        let cors = if self.allowed_origins.is_empty() {
+            warp::cors().allow_any_origin()
+        } else {
+            warp::cors().allow_origins(self.allowed_origins.iter().map(String::as_str))
+        }
+        .allow_methods(["GET", "POST"])
+        .allow_headers(["authorization", "content-type"]);
+
+        let rest_routes = allowed_origin(self.allowed_origins.clone()).and(rest::routes(
+            self.registry.clone(),
+            self.command_manager.clone(),
+            self.auth.clone(),
+        ));
+        let registry = self.registry.clone();
+        let metrics_route = warp::path!("metrics")
+            .and(warp::get())
+            .and(authenticated(self.auth.clone()))
+            .map(move |_identity: ClientIdentity| {
+                warp::reply::with_header(
+                    metrics().render(&registry),
+                    "content-type",
+                    "text/plain; version=0.0.4",
+                )
             });
 
-        let routes = ws_route.with(warp::cors().allow_any_origin());
+        let healthz = warp::path!("healthz")
+            .and(warp::get())
+            .map(|| warp::reply::json(&serde_json::json!({ "status": "ok" })));
+
+        let ready = self.ready.clone();
+        let readyz = warp::path!("readyz").and(warp::get()).map(move || {
+            let (status, body) = if ready.load(Ordering::Relaxed) {
+                (StatusCode::OK, "ready")
+            } else {
+                (StatusCode::SERVICE_UNAVAILABLE, "shutting_down")
+            };
+            warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({ "status": body })),
+                status,
+            )
+        });
 
-        warp::serve(routes).run(([127, 0, 0, 1], port)).await;
+        let routes = healthz
+            .or(readyz)
+            .or(ws_route)
+            .or(metrics_route)
+            .or(rest_routes)
+            .recover(handle_rejection)
+            .with(cors);
+
+        let tls_acceptor = match tls {
+            Some(config) => {
+                let acceptor = ReloadableTlsAcceptor::new(config)?;
+                #[cfg(unix)]
+                acceptor.reload_on_sighup()?;
+                Some(acceptor)
+            }
+            None => None,
+        };
+
+        let (stop_accepting, stop_signal) = watch::channel(false);
+        let mut listeners = Vec::new();
+        for addr in addrs {
+            let listener = TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("Failed to bind {addr}"))?;
+            let server = warp::serve(routes.clone());
+            let mut stop_signal = stop_signal.clone();
+            let stop_signal = async move {
+                let _ = stop_signal.wait_for(|stop| *stop).await;
+            };
+
+            listeners.push(match &tls_acceptor {
+                Some(acceptor) => {
+                    info!("Listening on wss://{addr}/ws and https://{addr}/devices");
+                    let connections = tls_incoming(incoming(listener), acceptor.clone());
+                    tokio::spawn(
+                        server.serve_incoming_with_graceful_shutdown(connections, stop_signal),
+                    )
+                }
+                None => {
+                    info!("Listening on ws://{addr}/ws and http://{addr}/devices");
+                    let connections = incoming(listener).map(Ok::<_, std::io::Error>);
+                    tokio::spawn(
+                        server.serve_incoming_with_graceful_shutdown(connections, stop_signal),
+                    )
+                }
+            });
+        }
+
+        self.ready.store(true, Ordering::Relaxed);
+        shutdown.await;
+
+        // Keep serving while load balancers see /readyz fail and take the server out of rotation
+        self.ready.store(false, Ordering::Relaxed);
+        if !self.readiness_drain.is_zero() {
+            info!(
+                "Shutting down, reporting not ready for {:?} before closing",
+                self.readiness_drain
+            );
+            tokio::time::sleep(self.readiness_drain).await;
+        }
+
+        info!(
+            "Shutting down, waiting up to {:?} for devices and clients",
+            self.shutdown_timeout
+        );
+        let _ = stop_accepting.send(true);
+
+        let deadline = Instant::now() + self.shutdown_timeout;
+        let sessions = self.registry.drain().await;
+        let mut channels = Vec::new();
+        if tokio::time::timeout_at(deadline, self.shutdown_devices(&sessions, &mut channels))
+            .await
+            .is_err()
+        {
+            warn!("Devices did not shut down in time");
+        }
+        // Also after a timeout, so every client still gets a close frame
+        for channel in &channels {
+            channel.close(1001, "Server shutting down");
+        }
+        if tokio::time::timeout_at(deadline, futures::future::join_all(listeners))
+            .await
+            .is_err()
+        {
+            warn!("Open HTTP requests did not finish in time");
+        }
+
+        info!("Server stopped");
+        Ok(())
+    }
+
+    /// Tells the clients of `sessions` the server is going away, adding their channels to
+    /// `channels`, removes all devices from Spotify Connect and waits for the clients to take
+    /// their queued messages. Closing the channels is left to the caller.
+    async fn shutdown_devices(
+        &self,
+        sessions: &[Arc<Mutex<ClientSession>>],
+        channels: &mut Vec<ClientChannel>,
+    ) {
+        let notice = serde_json::json!({
+            "type": "server_shutdown",
+            "data": {
+                "timeout_ms": self.shutdown_timeout.as_millis() as u64,
+            }
+        });
+
+        for session in sessions {
+            let state = session.lock().await;
+            state.channel.send_json(&notice);
+            channels.push(state.channel.clone());
+        }
+
+        futures::future::join_all(sessions.iter().map(|session| async {
+            let mut state = session.lock().await;
+            let devices: Vec<_> = state.devices.drain().collect();
+            futures::future::join_all(devices.into_iter().map(|(device_id, mut device)| {
+                self.registry.unregister_device(&device_id);
+                async move { device.close(RemovalReason::ServerShutdown).await }
+            }))
+            .await;
+        }))
+        .await;
+
+        futures::future::join_all(channels.iter().map(ClientChannel::flushed)).await;
     }
 
-    async fn handle_client_connection(self, ws: WebSocket) {
-        info!("New client connecting");
+    async fn handle_client_connection(
+        self,
+        ws: WebSocket,
+        identity: ClientIdentity,
+        params: ConnectionParams,
+    ) {
+        info!(
+            "Client {} connecting with {:?} audio framing",
+            identity.client_id, params.audio_framing
+        );
 
-        let (ws_sender, mut ws_receiver) = ws.split();
-        let (tx, rx) = mpsc::unbounded_channel();
+        let (mut ws_sender, mut ws_receiver) = ws.split();
+        let audio_overflow = params.audio_overflow.unwrap_or(self.audio_overflow);
+        let (tx, mut outgoing) = outgoing_queue(self.audio_queue_len, audio_overflow);
 
-        let rx_stream = UnboundedReceiverStream::new(rx);
-        tokio::task::spawn(rx_stream.forward(ws_sender).map(|result| {
-            if let Err(e) = result {
-                error!("Error sending websocket msg: {e}");
+        tokio::task::spawn(async move {
+            while let Some(message) = outgoing.recv().await {
+                if let Err(e) = ws_sender.send(message).await {
+                    error!("Error sending websocket msg: {e}");
+                    return;
+                }
             }
-        }));
+            // Queue closed by an overflow or shutdown, the close frame has been sent
+            let _ = ws_sender.close().await;
+        });
+
+        let attached = self
+            .registry
+            .attach(
+                params.resume_token.as_deref(),
+                &identity.client_id,
+                tx.clone(),
+                params.audio_framing,
+            )
+            .await;
 
-        let connection_state = Arc::new(Mutex::new(ConnectionState::new()));
+        let connection_response = {
+            let session = attached.session.lock().await;
+            metrics().connection_opened(session.id, tx.clone());
+            if attached.resumed {
+                info!(
+                    "Client resumed session with {} device(s)",
+                    session.devices.len()
+                );
+            }
 
-        let connection_response = ConnectionResponse {
-            status: "Connected to server".to_string(),
-            protocol_version: PROTOCOL_VERSION.to_string(),
+            ConnectionResponse {
+                status: "Connected to server".to_string(),
+                protocol_version: PROTOCOL_VERSION.to_string(),
+                audio_framing: params.audio_framing,
+                audio_overflow,
+                resume_token: session.resume_token.clone(),
+                resumed: attached.resumed,
+                devices: session.devices.keys().cloned().collect(),
+            }
         };
 
         if let Ok(response_json) = serde_json::to_string(&connection_response) {
-            if let Err(e) = tx.send(Ok(Message::text(response_json))) {
+            if let Err(e) = tx.send(Message::text(response_json)) {
                 error!("Error sending initial connection response: {e}");
+                self.disconnect(&attached, &tx).await;
                 return;
             }
         }
@@ -98,7 +384,7 @@ impl SpotifyServer {
 
             if let Ok(text) = msg.to_str() {
                 if let Err(e) = self
-                    .process_ws_message(text, &tx, connection_state.clone())
+                    .process_ws_message(text, &tx, &identity, attached.session.clone())
                     .await
                 {
                     error!("Error processing message: {e}");
@@ -108,65 +394,138 @@ impl SpotifyServer {
         }
 
         info!("Client disconnected");
+        self.disconnect(&attached, &tx).await;
+    }
+
+    async fn disconnect(&self, attached: &AttachedSession, tx: &OutgoingSender) {
+        let session_id = attached.session.lock().await.id;
+        metrics().connection_closed(session_id, tx);
+        self.registry
+            .detach(&attached.session, attached.generation)
+            .await;
     }
 
     async fn process_ws_message(
         &self,
         text: &str,
-        tx: &mpsc::UnboundedSender<WsResult<Message>>,
-        connection_state: Arc<Mutex<ConnectionState>>,
+        tx: &OutgoingSender,
+        identity: &ClientIdentity,
+        session: Arc<Mutex<ClientSession>>,
     ) -> Result<(), Box<dyn std::error::Error>> {
         let command_message: CommandMessage = match serde_json::from_str(text) {
             Ok(msg) => msg,
             Err(e) => {
-                let error_response = CommandResponse::error(format!("Invalid JSON format: {e}"));
+                metrics().command_executed(INVALID_COMMAND, false);
+                // Still try to correlate the error if the id itself is readable
+                let request_id = serde_json::from_str::<serde_json::Value>(text)
+                    .ok()
+                    .and_then(|value| value.get("request_id").cloned());
+                let error_response = CommandResponse::error(format!("Invalid JSON format: {e}"))
+                    .with_request_id(request_id);
                 let response_json = serde_json::to_string(&error_response)?;
-                tx.send(Ok(Message::text(response_json)))?;
+                tx.send(Message::text(response_json))?;
                 return Ok(());
             }
         };
 
+        let request_id = command_message.request_id.clone();
         let response = {
-            let mut state = connection_state.lock().await;
+            let mut state = session.lock().await;
 
             match Command::from_message(command_message) {
                 Ok((device_id, cmd)) => match cmd {
-                    Command::CreateDevice { token, device_name } => {
+                    Command::CreateDevice {
+                        token,
+                        device_name,
+                        options,
+                    } => {
                         let device_id = Uuid::new_v4().to_string();
+                        let device_name =
+                            device_name.unwrap_or_else(|| format!("Blockyspot {device_id}"));
+                        let stream_index = state.next_stream_index();
                         let mut spotify = SpotifyClient::new();
-                        match spotify
+                        let response = match spotify
                             .initialize(
                                 &token,
-                                device_name.unwrap_or_else(|| format!("Blockyspot {device_id}")),
-                                tx.clone(),
+                                device_name.clone(),
+                                state.channel.clone(),
                                 device_id.clone(),
+                                stream_index,
+                                options,
                             )
                             .await
                         {
                             Ok(()) => {
+                                self.registry.register_device(
+                                    &session,
+                                    state.id,
+                                    spotify.events(),
+                                    &device_id,
+                                    &device_name,
+                                    &identity.client_id,
+                                );
                                 state.devices.insert(device_id.clone(), spotify);
                                 CommandResponse::success(
                                     "Connected to Spotify",
-                                    Some(serde_json::json!({ "device_id": device_id })),
+                                    Some(serde_json::json!({
+                                        "device_id": device_id,
+                                        "stream_index": stream_index,
+                                    })),
                                 )
                             }
                             Err(e) => CommandResponse::error(format!("Failed to connect: {e}")),
-                        }
+                        };
+                        metrics().command_executed("CreateDevice", response.success);
+                        response
+                    }
+                    Command::RemoveDevice => {
+                        let response = match state.devices.remove(&device_id) {
+                            Some(mut spotify) => {
+                                self.registry.unregister_device(&device_id);
+                                spotify.close(RemovalReason::Removed).await;
+                                CommandResponse::success("Device removed", None)
+                            }
+                            None => CommandResponse::error("Device not found"),
+                        };
+                        metrics().command_executed("RemoveDevice", response.success);
+                        response
                     }
                     cmd => {
                         if let Some(spotify) = state.devices.get_mut(&device_id) {
-                            self.command_manager.execute(cmd, spotify)
+                            let response = self.command_manager.execute(cmd, spotify);
+                            // Lookups can take a while, don't hold up the session meanwhile
+                            drop(state);
+                            response.await
                         } else {
                             CommandResponse::error("Device not found")
                         }
                     }
                 },
-                Err(e) => CommandResponse::error(format!("Invalid command: {e}")),
+                Err(e) => {
+                    metrics().command_executed(INVALID_COMMAND, false);
+                    CommandResponse::error(format!("Invalid command: {e}"))
+                }
             }
         };
 
-        let response_json = serde_json::to_string(&response)?;
-        tx.send(Ok(Message::text(response_json)))?;
+        let response_json = serde_json::to_string(&response.with_request_id(request_id))?;
+        tx.send(Message::text(response_json))?;
         Ok(())
     }
 }
+
+/// Accepted connections on `listener`, retrying on transient errors such as running out of
+/// file descriptors instead of stopping the server.
+fn incoming(listener: TcpListener) -> impl Stream<Item = TcpStream> {
+    futures::stream::unfold(listener, |listener| async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => return Some((stream, listener)),
+                Err(e) => {
+                    error!("Failed to accept connection: {e}");
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+            }
+        }
+    })
+}
@@ -1,27 +1,74 @@
-use crate::server::WsResult;
-use crate::ws_sink::create_ws_sink;
+use crate::client_channel::ClientChannel;
+use crate::commands::{DeviceOptions, LoadParams, StartTrack};
+use crate::cover_art::CoverArt;
+use crate::device_events::DeviceEvents;
+use crate::events::player_event_message;
+use crate::metadata::{cover_art_message, track_metadata_message, TrackMetadata};
+use crate::metrics::{metrics, SpircExit};
+use crate::playback_state::PlaybackState;
+use crate::track_position::SinkPlayerEvents;
+use crate::ws_sink::{create_ws_sink, Encoding};
 use anyhow::Result;
-use librespot::connect::{ConnectConfig, Spirc};
+use futures::{FutureExt, StreamExt};
+use librespot::connect::{
+    ConnectConfig, LoadContextOptions, LoadRequest, LoadRequestOptions, Options, PlayingTrack,
+    Spirc,
+};
 use librespot::core::authentication::Credentials;
 use librespot::core::cache::Cache;
 use librespot::core::config::SessionConfig;
+use librespot::core::dealer::protocol::Message;
 use librespot::core::session::Session;
+use librespot::core::spotify_id::SpotifyId;
 use librespot::playback::{
-    config::{AudioFormat, PlayerConfig},
+    audio_backend::Sink,
+    config::{Bitrate, PlayerConfig},
     mixer,
     mixer::MixerConfig,
     player::Player,
     player::PlayerEvent,
     player::SinkStatus,
 };
-use std::sync::Arc;
-use tokio::sync::mpsc;
+use librespot::protocol::connect::ClusterUpdate;
+use log::{info, warn};
+use serde::Serialize;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
 use tokio::task;
-use warp::ws::Message;
 
 const CACHE: &str = ".cache";
 const CACHE_FILES: &str = ".cache/files";
 
+/// How long [`SpotifyClient::close`] waits for Spirc to say goodbye to Spotify.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Topic of the dealer messages carrying Connect state updates.
+const CLUSTER_UPDATE_URI: &str = "hm://connect-state/v1/cluster";
+
+/// A track coming up after the current one, as listed in the Connect state.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueuedTrack {
+    pub uri: String,
+    /// Where the track comes from: `queue` for tracks queued by a user, `context` for the
+    /// rest of the playlist or album, `autoplay` for recommendations.
+    pub provider: String,
+}
+
+/// Why a device went away, sent in its `device_removed` event.
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemovalReason {
+    /// The client asked for it with `RemoveDevice`.
+    Removed,
+    /// The client disconnected and did not resume within the grace period.
+    SessionExpired,
+    /// The server is shutting down.
+    ServerShutdown,
+}
+
 macro_rules! spirc_call {
     ($self:expr, $method:ident) => {
         if let Some(spirc) = &$self.spirc {
@@ -45,10 +92,21 @@ pub struct SpotifyClient {
     player: Option<Arc<Player>>,
     spirc: Option<Arc<Spirc>>,
     spirc_task: Option<tokio::task::JoinHandle<()>>,
+    /// Set before Spirc is shut down so its task can tell a requested exit from a lost session.
+    closing: Arc<AtomicBool>,
     device_name: String,
     device_id: String,
-    ws_sender: Option<mpsc::UnboundedSender<WsResult<Message>>>,
+    events: DeviceEvents,
     player_event_task: Option<task::JoinHandle<()>>,
+    /// Metadata lookup of the current track, replaced on every track change.
+    metadata_task: Arc<Mutex<Option<task::JoinHandle<()>>>>,
+    backpressure_task: Option<task::JoinHandle<()>>,
+    connect_state_task: Option<task::JoinHandle<()>>,
+    /// Upcoming tracks from the latest Connect state, including those queued from other apps.
+    queue: Arc<Mutex<Vec<QueuedTrack>>>,
+    state: Arc<Mutex<PlaybackState>>,
+    /// Metadata of the current track, refreshed on every track change.
+    current_metadata: Arc<Mutex<Option<TrackMetadata>>>,
 }
 
 impl SpotifyClient {
@@ -60,12 +118,14 @@ impl SpotifyClient {
         &mut self,
         token: impl Into<String>,
         device_name: String,
-        ws_sender: mpsc::UnboundedSender<WsResult<Message>>,
+        channel: ClientChannel,
         device_id: String,
+        stream_index: u16,
+        options: DeviceOptions,
     ) -> Result<()> {
         self.device_name = device_name.clone();
-        let ws_sender_clone = ws_sender.clone();
-        self.ws_sender = Some(ws_sender);
+        *self.state.lock().unwrap() = PlaybackState::new(device_name.clone());
+        self.events = DeviceEvents::new(channel);
         self.device_id = device_id;
 
         let connect_config = ConnectConfig {
@@ -73,13 +133,42 @@ impl SpotifyClient {
             ..Default::default()
         };
         let session_config = SessionConfig::default();
-        let player_config = PlayerConfig::default();
-        let audio_format = AudioFormat::default();
+        // The output bitrate only picks the Spotify stream for passthrough, decoded output is
+        // re-encoded at its own bitrate
+        let player_config = match options.output.encoding {
+            Encoding::Passthrough => PlayerConfig {
+                passthrough: true,
+                bitrate: match options.output.bitrate() {
+                    96_000 => Bitrate::Bitrate96,
+                    320_000 => Bitrate::Bitrate320,
+                    _ => Bitrate::Bitrate160,
+                },
+                ..Default::default()
+            },
+            _ => PlayerConfig::default(),
+        };
         let mixer_config = MixerConfig::default();
 
+        let channel_clone = self.events.channel().clone();
         let device_id_clone = self.device_id.clone();
-        let sink_builder =
-            move || create_ws_sink(ws_sender_clone.clone(), audio_format, device_id_clone);
+        let pause_requests = Arc::new(Notify::new());
+        let pause_requests_clone = pause_requests.clone();
+        let output = options.output;
+        let audio_lead = Duration::from_millis(options.audio_lead_ms.into());
+        let sink_player_events = SinkPlayerEvents::default();
+        let sink_player_events_clone = sink_player_events.clone();
+        // Built up front so output the sink can't produce fails the device instead of the player
+        let sink = create_ws_sink(
+            channel_clone,
+            output,
+            device_id_clone,
+            stream_index,
+            pause_requests_clone,
+            audio_lead,
+            sink_player_events_clone,
+        )
+        .map_err(|e| anyhow::anyhow!("Failed to create audio output: {e}"))?;
+        let sink_builder = move || -> Box<dyn Sink> { sink };
         let mixer_builder = mixer::find(None).unwrap();
 
         let cache = Cache::new(Some(CACHE), Some(CACHE), Some(CACHE_FILES), None)?;
@@ -95,83 +184,81 @@ impl SpotifyClient {
             mixer.get_soft_volume(),
             sink_builder,
         );
+        *sink_player_events.lock().unwrap() = Some(player.get_player_event_channel());
 
         // Set up sink event callbacks
-        let ws_sender_clone = self.ws_sender.clone();
+        let events_clone = self.events.clone();
         let device_id_clone = self.device_id.clone();
         player.set_sink_event_callback(Some(Box::new(move |event: SinkStatus| {
-            if let Some(sender) = &ws_sender_clone {
-                let event_json = serde_json::json!({
-                    "type": "sink_event",
-                    "device_id": device_id_clone,
-                    "data": {
-                        "status": format!("{:?}", event),
-                    }
-                });
-
-                if let Ok(msg) = serde_json::to_string(&event_json) {
-                    let _ = sender.send(Ok(Message::text(msg)));
+            let event_json = serde_json::json!({
+                "type": "sink_event",
+                "device_id": device_id_clone,
+                "data": {
+                    "status": format!("{:?}", event),
                 }
-            }
+            });
+            events_clone.send_json(&event_json);
         })));
 
         // Set up player event channel
         let mut event_channel = player.get_player_event_channel();
-        let ws_sender_clone = self.ws_sender.clone();
+        let events_clone = self.events.clone();
         let device_id_clone = self.device_id.clone();
+        let state = self.state.clone();
+        let current_metadata = self.current_metadata.clone();
+        let session_clone = session.clone();
+        let metadata_task = self.metadata_task.clone();
 
         // Spawn a task to handle player events
         let player_event_task = tokio::spawn(async move {
             while let Some(event) = event_channel.recv().await {
-                if let Some(sender) = &ws_sender_clone {
-                    let event_json = serde_json::json!({
-                        "type": "player_event",
-                        "device_id": device_id_clone,
-                        "data": {
-                            "event_type": format!("{:?}", event),
-                            "details": match &event {
-                                PlayerEvent::Playing { play_request_id, track_id, position_ms } => {
-                                    serde_json::json!({
-                                        "play_request_id": play_request_id,
-                                        "track_id": track_id.to_string(),
-                                        "position_ms": position_ms
-                                    })
-                                },
-                                PlayerEvent::Paused { play_request_id, track_id, position_ms } => {
-                                    serde_json::json!({
-                                        "play_request_id": play_request_id,
-                                        "track_id": track_id.to_string(),
-                                        "position_ms": position_ms
-                                    })
-                                },
-                                PlayerEvent::Stopped { play_request_id, track_id } => {
-                                    serde_json::json!({
-                                        "play_request_id": play_request_id,
-                                        "track_id": track_id.to_string()
-                                    })
-                                },
-                                PlayerEvent::Loading { play_request_id, track_id, position_ms } => {
-                                    serde_json::json!({
-                                        "play_request_id": play_request_id,
-                                        "track_id": track_id.to_string(),
-                                        "position_ms": position_ms
-                                    })
-                                },
-                                PlayerEvent::EndOfTrack { play_request_id, track_id } => {
-                                    serde_json::json!({
-                                        "play_request_id": play_request_id,
-                                        "track_id": track_id.to_string()
-                                    })
-                                },
-                                _ => serde_json::json!(null)
+                state.lock().unwrap().apply(&event);
+
+                if let PlayerEvent::TrackChanged { audio_item } = &event {
+                    // Resolve in the background so metadata lookups don't hold up player events
+                    let track_id = audio_item.track_id;
+                    let session = session_clone.clone();
+                    let events = events_clone.clone();
+                    let device_id = device_id_clone.clone();
+                    let current_metadata = current_metadata.clone();
+                    let cover_art_events = options.cover_art_events;
+                    let lookup = tokio::spawn(async move {
+                        let metadata = match TrackMetadata::fetch(&session, &track_id).await {
+                            Ok(metadata) => metadata,
+                            Err(e) => {
+                                warn!("Failed to resolve track metadata: {e}");
+                                return;
+                            }
+                        };
+
+                        events.send_json(&track_metadata_message(&device_id, &metadata));
+                        *current_metadata.lock().unwrap() = Some(metadata.clone());
+
+                        if cover_art_events {
+                            let cover_art = match metadata.fetch_cover_art(&session).await {
+                                Ok(cover_art) => cover_art,
+                                Err(e) => {
+                                    warn!("Failed to fetch cover art: {e}");
+                                    return;
+                                }
+                            };
+
+                            match cover_art.to_json(&metadata.track_id) {
+                                Ok(data) => {
+                                    events.send_json(&cover_art_message(&device_id, data));
+                                }
+                                Err(e) => warn!("Failed to convert cover art: {e}"),
                             }
                         }
                     });
-
-                    if let Ok(msg) = serde_json::to_string(&event_json) {
-                        let _ = sender.send(Ok(Message::text(msg)));
+                    // A newer track change cancels the lookup, so its results never overwrite
+                    // those of the track that replaced it
+                    if let Some(previous) = metadata_task.lock().unwrap().replace(lookup) {
+                        previous.abort();
                     }
                 }
+
+                events_clone.send_json(&player_event_message(&device_id_clone, &event));
             }
         });
 
@@ -188,14 +275,137 @@ impl SpotifyClient {
 
         let spirc = Arc::new(spirc);
 
+        // Pause when the client falls behind and its audio overflow policy asks for it
+        let spirc_clone = spirc.clone();
+        let device_id = self.device_id.clone();
+        self.backpressure_task = Some(tokio::spawn(async move {
+            loop {
+                pause_requests.notified().await;
+                info!("Pausing device {device_id}, its client is not keeping up with the audio");
+                if let Err(e) = spirc_clone.pause() {
+                    warn!("Failed to pause device {device_id}: {e}");
+                }
+            }
+        }));
+
+        // Spirc keeps the queue in the Connect state, which is shared by every device of the
+        // account, so tracks queued from the Spotify app show up as well
+        match session
+            .dealer()
+            .listen_for(CLUSTER_UPDATE_URI, Message::from_raw::<ClusterUpdate>)
+        {
+            Ok(mut cluster_updates) => {
+                let queue = self.queue.clone();
+                self.connect_state_task = Some(tokio::spawn(async move {
+                    while let Some(update) = cluster_updates.next().await {
+                        let update = match update {
+                            Ok(update) => update,
+                            Err(e) => {
+                                warn!("Failed to parse Connect state update: {e}");
+                                continue;
+                            }
+                        };
+                        *queue.lock().unwrap() = update
+                            .cluster
+                            .player_state
+                            .next_tracks
+                            .iter()
+                            // Delimiters mark the end of the context, not a track
+                            .filter(|track| !track.uri.starts_with("spotify:delimiter"))
+                            .map(|track| QueuedTrack {
+                                uri: track.uri.clone(),
+                                provider: track.provider.clone(),
+                            })
+                            .collect();
+                    }
+                }));
+            }
+            Err(e) => warn!("Failed to subscribe to Connect state updates: {e}"),
+        }
+
         self.session = Some(session);
         self.player = Some(player);
         self.spirc = Some(spirc);
-        self.spirc_task = Some(tokio::spawn(spirc_task));
+        let closing = self.closing.clone();
+        let device_id = self.device_id.clone();
+        self.spirc_task = Some(tokio::spawn(async move {
+            let exit = match AssertUnwindSafe(spirc_task).catch_unwind().await {
+                Ok(()) if closing.load(Ordering::Relaxed) => SpircExit::Shutdown,
+                Ok(()) => {
+                    warn!("Spirc task of device {device_id} stopped unexpectedly");
+                    SpircExit::Unexpected
+                }
+                Err(_) => {
+                    warn!("Spirc task of device {device_id} panicked");
+                    SpircExit::Panicked
+                }
+            };
+            metrics().spirc_task_exited(exit);
+        }));
 
         Ok(())
     }
 
+    /// Removes the device from Spotify Connect and waits for its tasks to finish.
+    ///
+    /// Emits a `device_removed` event once the device is gone. The client can't be used
+    /// afterwards.
+    pub async fn close(&mut self, reason: RemovalReason) {
+        self.closing.store(true, Ordering::Relaxed);
+        if let Some(spirc) = self.spirc.take() {
+            if let Err(e) = spirc.shutdown() {
+                warn!("Failed to shut down device {}: {e}", self.device_id);
+            }
+        }
+
+        if let Some(mut spirc_task) = self.spirc_task.take() {
+            match tokio::time::timeout(SHUTDOWN_TIMEOUT, &mut spirc_task).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Spirc task of device {} failed: {e}", self.device_id),
+                Err(_) => {
+                    warn!(
+                        "Spirc task of device {} did not stop in time, aborting",
+                        self.device_id
+                    );
+                    spirc_task.abort();
+                    metrics().spirc_task_exited(SpircExit::Aborted);
+                }
+            }
+        }
+
+        // Dropping the last player handle stops the sink and closes the event channel
+        self.player = None;
+        // Waited for, so neither can send an event after `device_removed`
+        if let Some(player_event_task) = self.player_event_task.take() {
+            player_event_task.abort();
+            let _ = player_event_task.await;
+        }
+        let metadata_task = self.metadata_task.lock().unwrap().take();
+        if let Some(metadata_task) = metadata_task {
+            metadata_task.abort();
+            let _ = metadata_task.await;
+        }
+        if let Some(backpressure_task) = self.backpressure_task.take() {
+            backpressure_task.abort();
+        }
+        if let Some(connect_state_task) = self.connect_state_task.take() {
+            connect_state_task.abort();
+        }
+        if let Some(session) = self.session.take() {
+            session.shutdown();
+        }
+
+        metrics().remove_device(&self.device_id);
+        info!("Device {} removed ({reason:?})", self.device_id);
+        self.events.send_json(&serde_json::json!({
+            "type": "device_removed",
+            "device_id": self.device_id,
+            "data": {
+                "reason": reason,
+            }
+        }));
+    }
+
     // Direct Spirc wrapper methods
     pub fn shutdown(&self) -> Result<()> {
         spirc_call!(self, shutdown)
@@ -256,4 +466,159 @@ impl SpotifyClient {
     pub fn activate(&self) -> Result<()> {
         spirc_call!(self, activate)
     }
+
+    pub fn load(&self, params: &LoadParams) -> Result<()> {
+        let options = LoadRequestOptions {
+            start_playing: params.start_playing,
+            seek_to: params.position_ms,
+            context_options: Some(LoadContextOptions::Options(Options {
+                shuffle: params.shuffle,
+                repeat: params.repeat,
+                repeat_track: params.repeat_track,
+            })),
+            playing_track: params.start_track.as_ref().map(|track| match track {
+                StartTrack::Index(index) => PlayingTrack::Index(*index),
+                StartTrack::Uri(uri) => PlayingTrack::Uri(uri.clone()),
+            }),
+        };
+
+        // A bare track has no context to resolve, so load it as a single track list
+        let request = if params.context_uri.starts_with("spotify:track:") {
+            LoadRequest::from_tracks(vec![params.context_uri.clone()], options)
+        } else {
+            LoadRequest::from_context_uri(params.context_uri.clone(), options)
+        };
+
+        spirc_call!(self, load, request)
+    }
+
+    pub fn add_to_queue(&self, uri: &str) -> Result<()> {
+        let track_id = SpotifyId::from_uri(uri)
+            .map_err(|e| anyhow::anyhow!("Invalid track URI {uri}: {e}"))?;
+        spirc_call!(self, add_to_queue, track_id)
+    }
+
+    pub fn clear_queue(&self) -> Result<()> {
+        spirc_call!(self, clear_queue)
+    }
+
+    /// Upcoming tracks as of the last Connect state update, changes made through this client
+    /// show up once Spotify has confirmed them.
+    pub fn queue(&self) -> Vec<QueuedTrack> {
+        self.queue.lock().unwrap().clone()
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        self.state.lock().unwrap().snapshot()
+    }
+
+    pub fn events(&self) -> &DeviceEvents {
+        &self.events
+    }
+
+    /// What metadata lookups need from this device, to run them without holding on to it.
+    pub fn metadata_source(&self) -> MetadataSource {
+        MetadataSource {
+            session: self.session.clone(),
+            current_metadata: self.current_metadata.clone(),
+        }
+    }
+}
+
+/// Looks up track metadata and cover art through a device's session.
+#[derive(Clone)]
+pub struct MetadataSource {
+    session: Option<Session>,
+    current_metadata: Arc<Mutex<Option<TrackMetadata>>>,
+}
+
+impl MetadataSource {
+    /// Returns metadata for `uri`, or for the current track if no URI is given.
+    pub async fn track_metadata(&self, uri: Option<&str>) -> Result<TrackMetadata> {
+        let Some(uri) = uri else {
+            return self
+                .current_metadata
+                .lock()
+                .unwrap()
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("No track metadata available yet"));
+        };
+
+        let Some(session) = &self.session else {
+            anyhow::bail!("Spotify Connect device not initialized")
+        };
+        let track_id = SpotifyId::from_uri(uri)
+            .map_err(|e| anyhow::anyhow!("Invalid track URI {uri}: {e}"))?;
+        TrackMetadata::fetch(session, &track_id).await
+    }
+
+    /// Returns the cover of `uri`, or of the current track if no URI is given.
+    pub async fn cover_art(&self, uri: Option<&str>) -> Result<(TrackMetadata, CoverArt)> {
+        let Some(session) = &self.session else {
+            anyhow::bail!("Spotify Connect device not initialized")
+        };
+        let metadata = self.track_metadata(uri).await?;
+        let cover_art = metadata.fetch_cover_art(session).await?;
+        Ok((metadata, cover_art))
+    }
+}
+
+impl Drop for SpotifyClient {
+    fn drop(&mut self) {
+        // Safety net for clients dropped without `close`, so no device lingers in Spotify
+        if let Some(spirc) = &self.spirc {
+            self.closing.store(true, Ordering::Relaxed);
+            let _ = spirc.shutdown();
+            metrics().remove_device(&self.device_id);
+        }
+        if let Some(player_event_task) = self.player_event_task.take() {
+            player_event_task.abort();
+        }
+        if let Some(metadata_task) = self.metadata_task.lock().unwrap().take() {
+            metadata_task.abort();
+        }
+        if let Some(backpressure_task) = self.backpressure_task.take() {
+            backpressure_task.abort();
+        }
+        if let Some(connect_state_task) = self.connect_state_task.take() {
+            connect_state_task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_channel::{outgoing_queue, AudioOverflowPolicy};
+    use crate::ws_sink::AudioFraming;
+
+    #[tokio::test]
+    async fn no_event_follows_device_removed() {
+        let channel = ClientChannel::default();
+        let (sender, mut receiver) = outgoing_queue(4, AudioOverflowPolicy::default());
+        channel.attach(sender, AudioFraming::Json);
+
+        let mut client = SpotifyClient::new();
+        client.device_id = "device-a".to_string();
+        client.events = DeviceEvents::new(channel);
+
+        // A cover art lookup that only finishes once the device is being torn down
+        let events = client.events.clone();
+        *client.metadata_task.lock().unwrap() = Some(tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            events.send_json(&serde_json::json!({ "type": "cover_art" }));
+        }));
+
+        client.close(RemovalReason::Removed).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut types = Vec::new();
+        while let Ok(Some(message)) =
+            tokio::time::timeout(Duration::from_millis(10), receiver.recv()).await
+        {
+            let event: serde_json::Value = serde_json::from_str(message.to_str().unwrap()).unwrap();
+            types.push(event["type"].as_str().unwrap().to_string());
+        }
+        assert_eq!(types, ["device_removed"]);
+    }
 }
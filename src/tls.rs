@@ -0,0 +1,124 @@
+use anyhow::{Context, Result};
+use futures::{Stream, StreamExt};
+use log::{error, info, warn};
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Handshakes that take longer than this are dropped so they can't pile up.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// PEM file with the PKCS#8, PKCS#1 or SEC1 private key
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    fn load(&self) -> Result<ServerConfig> {
+        let cert_file = std::fs::File::open(&self.cert_path)
+            .with_context(|| format!("Failed to open {}", self.cert_path.display()))?;
+        let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Failed to read {}", self.cert_path.display()))?;
+
+        let key_file = std::fs::File::open(&self.key_path)
+            .with_context(|| format!("Failed to open {}", self.key_path.display()))?;
+        let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))
+            .with_context(|| format!("Failed to read {}", self.key_path.display()))?
+            .with_context(|| format!("No private key found in {}", self.key_path.display()))?;
+
+        ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .context("Invalid certificate or key")
+    }
+}
+
+/// TLS acceptor whose certificate can be swapped without restarting the listeners.
+#[derive(Clone)]
+pub struct ReloadableTlsAcceptor {
+    config: TlsConfig,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl ReloadableTlsAcceptor {
+    pub fn new(config: TlsConfig) -> Result<Self> {
+        let acceptor = TlsAcceptor::from(Arc::new(config.load()?));
+        Ok(Self {
+            config,
+            acceptor: Arc::new(RwLock::new(acceptor)),
+        })
+    }
+
+    /// Re-reads certificate and key from disk. New connections use them, existing ones are kept.
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = TlsAcceptor::from(Arc::new(self.config.load()?));
+        *self.acceptor.write().unwrap() = acceptor;
+        Ok(())
+    }
+
+    /// Reloads the certificate whenever the process receives SIGHUP.
+    #[cfg(unix)]
+    pub fn reload_on_sighup(&self) -> Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let acceptor = self.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match acceptor.reload() {
+                    Ok(()) => info!("Reloaded TLS certificate"),
+                    // Keep serving the old certificate rather than going down
+                    Err(e) => error!("Failed to reload TLS certificate: {e:#}"),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn current(&self) -> TlsAcceptor {
+        self.acceptor.read().unwrap().clone()
+    }
+}
+
+/// Wraps accepted TCP connections in TLS. Handshakes run concurrently so a slow client can't
+/// hold up the others, failed ones are logged and skipped.
+pub fn tls_incoming(
+    connections: impl Stream<Item = TcpStream> + Send + 'static,
+    acceptor: ReloadableTlsAcceptor,
+) -> impl Stream<Item = std::io::Result<TlsStream<TcpStream>>> {
+    let (tx, rx) = mpsc::channel(64);
+
+    tokio::spawn(async move {
+        let mut connections = std::pin::pin!(connections);
+        while let Some(stream) = connections.next().await {
+            if tx.is_closed() {
+                break;
+            }
+
+            let acceptor = acceptor.current();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = tx.send(Ok(stream)).await;
+                    }
+                    Ok(Err(e)) => warn!("TLS handshake failed: {e}"),
+                    Err(_) => warn!("TLS handshake timed out"),
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
@@ -0,0 +1,268 @@
+use librespot::playback::player::{PlayerEvent, PlayerEventChannel};
+use librespot::playback::SAMPLE_RATE;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Player events for a sink, handed over once the player that owns the sink exists.
+///
+/// The player sends its events from the thread that writes to the sink, before the audio
+/// that follows them, so a sink that reads its own subscription sees every event ahead of
+/// the first sample it applies to.
+pub type SinkPlayerEvents = Arc<Mutex<Option<PlayerEventChannel>>>;
+
+/// Track position reported by the player, from the decoder frame it applies to.
+#[derive(Debug, Clone, Copy)]
+struct Anchor {
+    frame: u64,
+    position_ms: u32,
+}
+
+/// Decoded frame a new track starts at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackBoundary {
+    pub frame: u64,
+    pub play_request_id: u64,
+    pub track_id: String,
+    /// Where in the track playback starts.
+    pub position_ms: u32,
+}
+
+/// Maps decoded sample frames back to positions in the track and finds where tracks start.
+///
+/// Frames are counted at the decoder's sample rate since the stream started. The player
+/// reports where playback starts, seeks and corrects to, and every later frame is that
+/// position plus the audio decoded since. Frames that were decoded before a report still
+/// map to the position before it, as long as they are asked for in order.
+///
+/// A track starts when the player loads or plays a track under a new play request, so
+/// playing the same track again also starts a new one.
+#[derive(Debug, Default)]
+pub struct TrackPosition {
+    anchors: VecDeque<Anchor>,
+    frames: u64,
+    /// Play request and track of the last track start.
+    current: Option<(u64, String)>,
+    /// Track starts not taken yet, oldest first.
+    boundaries: VecDeque<TrackBoundary>,
+}
+
+impl TrackPosition {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Picks up the position from an event, as of the next frame decoded.
+    pub fn apply(&mut self, event: &PlayerEvent) {
+        match event {
+            PlayerEvent::Loading {
+                play_request_id,
+                track_id,
+                position_ms,
+            }
+            | PlayerEvent::Playing {
+                play_request_id,
+                track_id,
+                position_ms,
+            } => {
+                self.track_started(*play_request_id, track_id.to_string(), *position_ms);
+                self.anchor(*position_ms);
+            }
+            PlayerEvent::Seeked { position_ms, .. }
+            | PlayerEvent::PositionCorrection { position_ms, .. } => self.anchor(*position_ms),
+            PlayerEvent::Stopped { .. } => {
+                self.anchors.clear();
+                self.current = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn track_started(&mut self, play_request_id: u64, track_id: String, position_ms: u32) {
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|(id, track)| *id == play_request_id && *track == track_id)
+        {
+            return;
+        }
+        self.current = Some((play_request_id, track_id.clone()));
+
+        // Nothing of a track that was replaced before any of it was decoded is played
+        if self
+            .boundaries
+            .back()
+            .is_some_and(|b| b.frame == self.frames)
+        {
+            self.boundaries.pop_back();
+        }
+        self.boundaries.push_back(TrackBoundary {
+            frame: self.frames,
+            play_request_id,
+            track_id,
+            position_ms,
+        });
+    }
+
+    /// Takes the oldest track start before decoded frame `frame`.
+    pub fn take_boundary_before(&mut self, frame: u64) -> Option<TrackBoundary> {
+        if self.boundaries.front()?.frame < frame {
+            self.boundaries.pop_front()
+        } else {
+            None
+        }
+    }
+
+    fn anchor(&mut self, position_ms: u32) {
+        // A later report for the same frame replaces the earlier one
+        if self.anchors.back().is_some_and(|a| a.frame == self.frames) {
+            self.anchors.pop_back();
+        }
+        self.anchors.push_back(Anchor {
+            frame: self.frames,
+            position_ms,
+        });
+    }
+
+    /// Records `frames` decoded sample frames.
+    pub fn advance(&mut self, frames: u64) {
+        self.frames += frames;
+    }
+
+    /// Position in the track of decoded frame `frame`, if the player has reported one.
+    ///
+    /// Reports older than the one `frame` falls under are forgotten.
+    pub fn position_ms(&mut self, frame: u64) -> Option<u32> {
+        while self.anchors.get(1).is_some_and(|next| next.frame <= frame) {
+            self.anchors.pop_front();
+        }
+        let anchor = self.anchors.front().filter(|a| a.frame <= frame)?;
+        let elapsed_ms = (frame - anchor.frame) * 1000 / u64::from(SAMPLE_RATE);
+        Some(anchor.position_ms.saturating_add(elapsed_ms as u32))
+    }
+
+    /// Starts counting frames from 0 for a new stream, at the position reached so far.
+    pub fn restart(&mut self) {
+        let position_ms = self.anchors.back().map(|anchor| {
+            let elapsed_ms = (self.frames - anchor.frame) * 1000 / u64::from(SAMPLE_RATE);
+            anchor.position_ms.saturating_add(elapsed_ms as u32)
+        });
+
+        self.anchors.clear();
+        // Only the next stream can still play the tracks that haven't started yet
+        for boundary in &mut self.boundaries {
+            boundary.frame = 0;
+        }
+        while self.boundaries.len() > 1 {
+            self.boundaries.pop_front();
+        }
+        self.frames = 0;
+        if let Some(position_ms) = position_ms {
+            self.anchor(position_ms);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = SAMPLE_RATE as u64;
+
+    #[test]
+    fn unknown_until_the_player_reports_a_position() {
+        let mut track = TrackPosition::new();
+        track.advance(RATE);
+        assert_eq!(track.position_ms(0), None);
+
+        track.anchor(10_000);
+        assert_eq!(track.position_ms(RATE - 1), None);
+        assert_eq!(track.position_ms(RATE), Some(10_000));
+        assert_eq!(track.position_ms(2 * RATE), Some(11_000));
+    }
+
+    #[test]
+    fn frames_before_a_seek_keep_the_old_position() {
+        let mut track = TrackPosition::new();
+        track.anchor(0);
+        track.advance(RATE);
+        track.anchor(60_000);
+        track.advance(RATE);
+
+        assert_eq!(track.position_ms(RATE / 2), Some(500));
+        assert_eq!(track.position_ms(RATE), Some(60_000));
+        assert_eq!(track.position_ms(RATE + RATE / 4), Some(60_250));
+    }
+
+    fn start(track: &mut TrackPosition, play_request_id: u64, track_id: &str) {
+        track.track_started(play_request_id, track_id.to_string(), 0);
+    }
+
+    #[test]
+    fn track_starts_at_the_frame_decoded_after_it_was_reported() {
+        let mut track = TrackPosition::new();
+        start(&mut track, 1, "a");
+        track.advance(RATE);
+        start(&mut track, 2, "b");
+        track.advance(RATE);
+
+        let first = track.take_boundary_before(1).unwrap();
+        assert_eq!((first.frame, first.track_id.as_str()), (0, "a"));
+        assert_eq!(track.take_boundary_before(RATE), None);
+        let second = track.take_boundary_before(RATE + 1).unwrap();
+        assert_eq!((second.frame, second.play_request_id), (RATE, 2));
+        assert_eq!(track.take_boundary_before(u64::MAX), None);
+    }
+
+    #[test]
+    fn only_new_play_requests_start_a_track() {
+        let mut track = TrackPosition::new();
+        start(&mut track, 1, "a");
+        track.advance(RATE);
+        // Playing after Loading, or after a pause
+        start(&mut track, 1, "a");
+        track.advance(RATE);
+        // The same track again, e.g. on repeat
+        start(&mut track, 2, "a");
+
+        assert_eq!(track.take_boundary_before(u64::MAX).unwrap().frame, 0);
+        assert_eq!(
+            track.take_boundary_before(u64::MAX).unwrap().frame,
+            2 * RATE
+        );
+        assert_eq!(track.take_boundary_before(u64::MAX), None);
+    }
+
+    #[test]
+    fn skipped_tracks_are_not_reported() {
+        let mut track = TrackPosition::new();
+        start(&mut track, 1, "a");
+        start(&mut track, 2, "b");
+        track.advance(RATE);
+
+        let boundary = track.take_boundary_before(u64::MAX).unwrap();
+        assert_eq!(boundary.track_id, "b");
+        assert_eq!(track.take_boundary_before(u64::MAX), None);
+    }
+
+    #[test]
+    fn pending_track_start_moves_to_the_next_stream() {
+        let mut track = TrackPosition::new();
+        track.advance(RATE);
+        start(&mut track, 1, "a");
+        track.restart();
+
+        assert_eq!(track.take_boundary_before(1).unwrap().frame, 0);
+    }
+
+    #[test]
+    fn restart_continues_from_the_position_reached() {
+        let mut track = TrackPosition::new();
+        track.anchor(5_000);
+        track.advance(3 * RATE);
+        track.restart();
+
+        assert_eq!(track.position_ms(0), Some(8_000));
+        track.advance(RATE);
+        assert_eq!(track.position_ms(RATE), Some(9_000));
+    }
+}
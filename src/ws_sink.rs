@@ -1,106 +1,708 @@
-use crate::server::WsResult;
+use crate::client_channel::{AudioSendOutcome, ClientChannel};
+use crate::flac::FlacEncoder;
+use crate::metrics::{metrics, DeviceMetrics};
+use crate::opus::{OpusEncoder, OPUS_BITRATES, OPUS_FRAME_DURATION_MS, OPUS_SAMPLE_RATES};
+use crate::pacing::PlaybackClock;
+use crate::resample::{ChannelMixer, Resampler};
+use crate::track_position::{SinkPlayerEvents, TrackPosition};
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
-use librespot::playback::audio_backend::{Open, Sink, SinkError, SinkResult};
+use librespot::playback::audio_backend::{Open, Sink, SinkResult};
 use librespot::playback::config::AudioFormat;
 use librespot::playback::convert::Converter;
 use librespot::playback::decoder::AudioPacket;
-use tokio::sync::mpsc;
+use librespot::playback::{NUM_CHANNELS, SAMPLE_RATE};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
 use warp::ws::Message;
 
+/// Size in bytes of the header prepended to every binary audio frame.
+pub const AUDIO_FRAME_HEADER_LEN: usize = 20;
+
+/// Track position in a binary audio frame header when the player hasn't reported one.
+pub const UNKNOWN_POSITION: u32 = u32::MAX;
+
+/// Output sample rates a sink can resample to.
+pub const SUPPORTED_SAMPLE_RATES: [u32; 10] = [
+    8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000, 88200, 96000,
+];
+
+/// Spotify stream bitrates passthrough output can use.
+pub const PASSTHROUGH_BITRATES: [u32; 3] = [96_000, 160_000, 320_000];
+
+/// Largest granule position step between two Ogg pages that is treated as continuous
+/// playback, bigger steps and steps back come from seeks and track changes.
+const MAX_GRANULE_STEP: u64 = 5 * SAMPLE_RATE as u64;
+
+/// Minimum time between two `stream_lagging` events of a sink.
+const LAG_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How audio is delivered to a client, chosen when the WebSocket connects.
+///
+/// Control messages and events are always JSON, only `audio_data` changes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioFraming {
+    /// Base64 encoded payload inside an `audio_data` JSON text message.
+    #[default]
+    Json,
+    /// Payload in a binary message prefixed with a 20 byte header:
+    ///
+    /// | offset | size | field                                                  |
+    /// |--------|------|--------------------------------------------------------|
+    /// | 0      | 1    | format tag, see [`AudioFrameFormat`]                   |
+    /// | 1      | 1    | reserved, always 0                                     |
+    /// | 2      | 2    | device stream index (u16, little endian)               |
+    /// | 4      | 4    | sequence number (u32, little endian)                   |
+    /// | 8      | 8    | index of the first sample (u64, little endian)         |
+    /// | 16     | 4    | track position in ms (u32, little endian), or [`UNKNOWN_POSITION`] |
+    Binary,
+}
+
+/// Format tag carried in the first byte of a binary audio frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AudioFrameFormat {
+    PcmS16Le = 0,
+    /// Ogg pages of Spotify's Vorbis stream.
+    OggVorbis = 1,
+    PcmS24Le = 2,
+    PcmF32Le = 3,
+    Opus = 4,
+    Flac = 5,
+}
+
+impl AudioFrameFormat {
+    fn packet_type(self) -> &'static str {
+        match self {
+            AudioFrameFormat::OggVorbis => "raw",
+            AudioFrameFormat::Opus | AudioFrameFormat::Flac => "packet",
+            _ => "samples",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            AudioFrameFormat::PcmS16Le => "pcm_s16le",
+            AudioFrameFormat::PcmS24Le => "pcm_s24le",
+            AudioFrameFormat::PcmF32Le => "pcm_f32le",
+            AudioFrameFormat::OggVorbis => "ogg_vorbis",
+            AudioFrameFormat::Opus => "opus",
+            AudioFrameFormat::Flac => "flac",
+        }
+    }
+}
+
+/// Sample encoding of PCM audio frames, all little endian and interleaved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleFormat {
+    #[default]
+    S16,
+    /// Signed 24 bit packed into 3 bytes.
+    S24,
+    F32,
+}
+
+impl SampleFormat {
+    fn frame_format(self) -> AudioFrameFormat {
+        match self {
+            SampleFormat::S16 => AudioFrameFormat::PcmS16Le,
+            SampleFormat::S24 => AudioFrameFormat::PcmS24Le,
+            SampleFormat::F32 => AudioFrameFormat::PcmF32Le,
+        }
+    }
+
+    fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::S16 => 2,
+            SampleFormat::S24 => 3,
+            SampleFormat::F32 => 4,
+        }
+    }
+
+    /// Converts interleaved samples to little endian bytes.
+    fn encode(self, samples: &[f64], converter: &mut Converter) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(samples.len() * self.bytes_per_sample());
+        match self {
+            SampleFormat::S16 => {
+                for sample in converter.f64_to_s16(samples) {
+                    bytes.extend_from_slice(&sample.to_le_bytes());
+                }
+            }
+            SampleFormat::S24 => {
+                for sample in converter.f64_to_s24(samples) {
+                    bytes.extend_from_slice(&sample.to_le_bytes()[..3]);
+                }
+            }
+            SampleFormat::F32 => {
+                for sample in converter.f64_to_f32(samples) {
+                    bytes.extend_from_slice(&sample.to_le_bytes());
+                }
+            }
+        }
+        bytes
+    }
+}
+
+/// How a sink encodes the audio it sends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    /// Uncompressed samples in the output's `sample_format`.
+    #[default]
+    Pcm,
+    /// One Opus packet per frame.
+    Opus,
+    /// One FLAC frame per frame, lossless in the output's `sample_format`.
+    Flac,
+    /// Spotify's Ogg/Vorbis stream as downloaded, without decoding, volume or resampling.
+    Passthrough,
+}
+
+/// Output a client asks for when creating a device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputSpec {
+    pub encoding: Encoding,
+    /// Sample format of PCM and FLAC output.
+    pub sample_format: SampleFormat,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u8>,
+    /// Duration of the audio in each `audio_data` frame.
+    pub frame_duration_ms: Option<u32>,
+    /// Opus bitrate, or the Spotify stream bitrate for passthrough, in bits per second.
+    pub bitrate: Option<u32>,
+    /// Opus encoder complexity, 0 to 10.
+    pub complexity: Option<u8>,
+}
+
+impl OutputSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        let sample_rate = self.sample_rate();
+        if !SUPPORTED_SAMPLE_RATES.contains(&sample_rate) {
+            return Err(format!(
+                "Unsupported sample_rate {sample_rate}, expected one of {SUPPORTED_SAMPLE_RATES:?}"
+            ));
+        }
+        if ChannelMixer::for_channels(self.channels()).is_none() {
+            return Err(format!(
+                "Unsupported channels {}, expected 1 or 2",
+                self.channels()
+            ));
+        }
+
+        match self.encoding {
+            Encoding::Pcm | Encoding::Flac => {
+                if self.bitrate.is_some() || self.complexity.is_some() {
+                    return Err("bitrate and complexity only apply to opus output".to_string());
+                }
+                if self.encoding == Encoding::Flac && self.sample_format == SampleFormat::F32 {
+                    return Err("flac output supports the s16 and s24 sample formats".to_string());
+                }
+                let frame_duration_ms = self.frame_duration_ms();
+                if !(10..=200).contains(&frame_duration_ms) {
+                    return Err("frame_duration_ms must be between 10 and 200".to_string());
+                }
+                if u64::from(sample_rate) * u64::from(frame_duration_ms) % 1000 != 0 {
+                    return Err(format!(
+                        "frame_duration_ms {frame_duration_ms} is not a whole number of samples at {sample_rate} Hz"
+                    ));
+                }
+            }
+            Encoding::Opus => {
+                if !OPUS_SAMPLE_RATES.contains(&sample_rate) {
+                    return Err(format!(
+                        "Unsupported sample_rate {sample_rate} for opus, expected one of {OPUS_SAMPLE_RATES:?}"
+                    ));
+                }
+                if self.frame_duration_ms() != OPUS_FRAME_DURATION_MS {
+                    return Err(format!(
+                        "opus output uses {OPUS_FRAME_DURATION_MS}ms frames"
+                    ));
+                }
+                if !OPUS_BITRATES.contains(&self.bitrate()) {
+                    return Err(format!(
+                        "bitrate must be between {} and {}",
+                        OPUS_BITRATES.start(),
+                        OPUS_BITRATES.end()
+                    ));
+                }
+                if self.complexity() > 10 {
+                    return Err("complexity must be between 0 and 10".to_string());
+                }
+                // Built once here, so a device is never created with settings libopus rejects
+                OpusEncoder::new(
+                    sample_rate,
+                    self.channels(),
+                    self.bitrate(),
+                    self.complexity(),
+                )
+                .map_err(|e| format!("Unsupported opus output: {e}"))?;
+            }
+            Encoding::Passthrough => {
+                if sample_rate != SAMPLE_RATE || self.channels() != NUM_CHANNELS {
+                    return Err(format!(
+                        "passthrough output is always {SAMPLE_RATE} Hz with {NUM_CHANNELS} channels"
+                    ));
+                }
+                if self.frame_duration_ms.is_some() || self.complexity.is_some() {
+                    return Err(
+                        "frame_duration_ms and complexity don't apply to passthrough output"
+                            .to_string(),
+                    );
+                }
+                if !PASSTHROUGH_BITRATES.contains(&self.bitrate()) {
+                    return Err(format!(
+                        "bitrate must be one of {PASSTHROUGH_BITRATES:?} for passthrough output"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate.unwrap_or(match self.encoding {
+            Encoding::Pcm | Encoding::Flac | Encoding::Passthrough => SAMPLE_RATE,
+            Encoding::Opus => 48_000,
+        })
+    }
+
+    pub fn channels(&self) -> u8 {
+        self.channels.unwrap_or(NUM_CHANNELS)
+    }
+
+    pub fn frame_duration_ms(&self) -> u32 {
+        self.frame_duration_ms.unwrap_or(match self.encoding {
+            Encoding::Pcm | Encoding::Flac | Encoding::Passthrough => 50,
+            Encoding::Opus => OPUS_FRAME_DURATION_MS,
+        })
+    }
+
+    /// Opus bitrate, 64 kbit/s per channel unless chosen, or the passthrough stream bitrate,
+    /// 160 kbit/s unless chosen.
+    pub fn bitrate(&self) -> u32 {
+        self.bitrate.unwrap_or(match self.encoding {
+            Encoding::Passthrough => 160_000,
+            _ => 64_000 * u32::from(self.channels()),
+        })
+    }
+
+    pub fn complexity(&self) -> u8 {
+        self.complexity.unwrap_or(10)
+    }
+
+    /// Sample frames (one sample per channel) in each audio frame.
+    pub fn frame_samples(&self) -> usize {
+        (u64::from(self.sample_rate()) * u64::from(self.frame_duration_ms()) / 1000) as usize
+    }
+}
+
+/// Turns a frame of interleaved output samples into an `audio_data` payload.
+enum FrameEncoder {
+    Pcm(SampleFormat),
+    Opus(OpusEncoder),
+    Flac(FlacEncoder, SampleFormat),
+    /// Never gets samples, the player hands over Ogg pages instead.
+    Passthrough {
+        bitrate: u32,
+    },
+}
+
+impl FrameEncoder {
+    fn new(output: &OutputSpec) -> Result<Self, String> {
+        Ok(match output.encoding {
+            Encoding::Pcm => FrameEncoder::Pcm(output.sample_format),
+            Encoding::Flac => FrameEncoder::Flac(
+                FlacEncoder::new(
+                    output.sample_rate(),
+                    output.channels(),
+                    output.sample_format.bytes_per_sample() as u32 * 8,
+                    output.frame_samples() as u16,
+                ),
+                output.sample_format,
+            ),
+            Encoding::Passthrough => FrameEncoder::Passthrough {
+                bitrate: output.bitrate(),
+            },
+            Encoding::Opus => FrameEncoder::Opus(
+                OpusEncoder::new(
+                    output.sample_rate(),
+                    output.channels(),
+                    output.bitrate(),
+                    output.complexity(),
+                )
+                .map_err(|e| format!("Failed to create opus encoder: {e}"))?,
+            ),
+        })
+    }
+
+    fn frame_format(&self) -> AudioFrameFormat {
+        match self {
+            FrameEncoder::Pcm(sample_format) => sample_format.frame_format(),
+            FrameEncoder::Opus(_) => AudioFrameFormat::Opus,
+            FrameEncoder::Flac(..) => AudioFrameFormat::Flac,
+            FrameEncoder::Passthrough { .. } => AudioFrameFormat::OggVorbis,
+        }
+    }
+
+    /// Starts a new stream.
+    fn reset(&mut self) {
+        match self {
+            FrameEncoder::Opus(encoder) => encoder.reset(),
+            FrameEncoder::Flac(encoder, _) => encoder.reset(),
+            FrameEncoder::Pcm(_) | FrameEncoder::Passthrough { .. } => {}
+        }
+    }
+
+    fn encode(&mut self, samples: &[f64], converter: &mut Converter) -> Result<Vec<u8>, String> {
+        match self {
+            FrameEncoder::Pcm(sample_format) => Ok(sample_format.encode(samples, converter)),
+            FrameEncoder::Opus(encoder) => encoder.encode(samples).map_err(|e| e.to_string()),
+            FrameEncoder::Flac(encoder, sample_format) => {
+                // Quantized the same way as PCM output, so both carry the same samples
+                let samples: Vec<i32> = match sample_format {
+                    SampleFormat::S24 => converter.f64_to_s24(samples),
+                    _ => converter
+                        .f64_to_s16(samples)
+                        .into_iter()
+                        .map(i32::from)
+                        .collect(),
+                };
+                Ok(encoder.encode(&samples))
+            }
+            FrameEncoder::Passthrough { .. } => {
+                Err("passthrough output can't encode samples".to_string())
+            }
+        }
+    }
+
+    /// Fields of the `audio_format` message describing the payloads.
+    fn describe(&self, info: &mut serde_json::Map<String, serde_json::Value>) {
+        info.insert("format".into(), self.frame_format().label().into());
+        match self {
+            FrameEncoder::Pcm(sample_format) => {
+                info.insert("encoding".into(), serde_json::json!(Encoding::Pcm));
+                info.insert("sample_format".into(), serde_json::json!(sample_format));
+                info.insert(
+                    "bit_depth".into(),
+                    (sample_format.bytes_per_sample() * 8).into(),
+                );
+            }
+            FrameEncoder::Flac(encoder, sample_format) => {
+                info.insert("encoding".into(), serde_json::json!(Encoding::Flac));
+                info.insert("sample_format".into(), serde_json::json!(sample_format));
+                info.insert(
+                    "bit_depth".into(),
+                    (sample_format.bytes_per_sample() * 8).into(),
+                );
+                info.insert(
+                    "stream_header".into(),
+                    BASE64.encode(encoder.stream_header()).into(),
+                );
+            }
+            FrameEncoder::Opus(encoder) => {
+                info.insert("encoding".into(), serde_json::json!(Encoding::Opus));
+                info.insert("bitrate".into(), encoder.bitrate().into());
+                info.insert("complexity".into(), encoder.complexity().into());
+                info.insert("pre_skip".into(), encoder.pre_skip().into());
+            }
+            FrameEncoder::Passthrough { bitrate } => {
+                info.insert("encoding".into(), serde_json::json!(Encoding::Passthrough));
+                info.insert("container".into(), "ogg".into());
+                info.insert("codec".into(), "vorbis".into());
+                info.insert("bitrate".into(), (*bitrate).into());
+            }
+        }
+    }
+}
+
 pub struct WebSocketSink {
-    sender: mpsc::UnboundedSender<WsResult<Message>>,
-    format: AudioFormat,
+    channel: ClientChannel,
+    output: OutputSpec,
+    encoder: FrameEncoder,
     is_active: bool,
+    mixer: ChannelMixer,
+    /// Converts from the decoder's sample rate, `None` when the output uses the same rate.
+    resampler: Option<Resampler>,
+    mixed: Vec<f64>,
+    /// Output samples waiting to fill a frame.
     buffer: Vec<f64>,
+    /// Interleaved samples in one audio frame.
     chunk_size: usize,
-    last_send_time: Option<std::time::Instant>,
+    clock: PlaybackClock,
+    /// Granule position of the last Ogg page sent in passthrough.
+    last_granule: Option<u64>,
+    /// The sink's own subscription to the player's events, for the track position.
+    player_events: SinkPlayerEvents,
+    track: TrackPosition,
     device_id: String,
+    stream_index: u16,
+    sequence: u32,
+    metrics: Arc<DeviceMetrics>,
+    /// Notified when the client can't keep up and the overflow policy asks to pause playback.
+    pause_requests: Arc<Notify>,
+    pause_requested: bool,
+    /// Frames dropped since the last `stream_lagging` event.
+    unreported_drops: u32,
+    last_lag_report: Option<Instant>,
 }
 
 impl Open for WebSocketSink {
-    fn open(_: Option<String>, format: AudioFormat) -> Self {
-        let (tx, _) = mpsc::unbounded_channel();
-
-        Self {
-            sender: tx,
-            format,
-            is_active: false,
-            buffer: Vec::new(),
-            chunk_size: 4410,
-            last_send_time: None,
-            device_id: String::new(),
-        }
+    fn open(_: Option<String>, _: AudioFormat) -> Self {
+        Self::new(
+            ClientChannel::default(),
+            OutputSpec::default(),
+            String::new(),
+            0,
+            Arc::default(),
+            Duration::ZERO,
+            Arc::default(),
+        )
+        .expect("default output is PCM, which has no encoder to fail")
     }
 }
 
 impl WebSocketSink {
-    pub fn with_sender(
-        sender: mpsc::UnboundedSender<WsResult<Message>>,
-        format: AudioFormat,
+    pub fn with_channel(
+        channel: ClientChannel,
+        output: OutputSpec,
         device_id: String,
-    ) -> Self {
-        Self {
-            sender,
-            format,
+        stream_index: u16,
+        pause_requests: Arc<Notify>,
+        lead: Duration,
+        player_events: SinkPlayerEvents,
+    ) -> Result<Self, String> {
+        let metrics = metrics().device(&device_id);
+        Ok(Self {
+            player_events,
+            ..Self::new(
+                channel,
+                output,
+                device_id,
+                stream_index,
+                pause_requests,
+                lead,
+                metrics,
+            )?
+        })
+    }
+
+    fn new(
+        channel: ClientChannel,
+        output: OutputSpec,
+        device_id: String,
+        stream_index: u16,
+        pause_requests: Arc<Notify>,
+        lead: Duration,
+        metrics: Arc<DeviceMetrics>,
+    ) -> Result<Self, String> {
+        let sample_rate = output.sample_rate();
+        let channels = output.channels();
+        Ok(Self {
+            channel,
+            output,
+            encoder: FrameEncoder::new(&output)?,
             is_active: false,
+            mixer: ChannelMixer::for_channels(channels).unwrap_or(ChannelMixer::Stereo),
+            resampler: (sample_rate != SAMPLE_RATE)
+                .then(|| Resampler::new(SAMPLE_RATE, sample_rate, channels.into())),
+            mixed: Vec::new(),
             buffer: Vec::new(),
-            chunk_size: 4410,
-            last_send_time: None,
+            chunk_size: output.frame_samples() * usize::from(channels),
+            clock: PlaybackClock::new(sample_rate, lead),
+            last_granule: None,
+            player_events: SinkPlayerEvents::default(),
+            track: TrackPosition::new(),
             device_id,
-        }
+            stream_index,
+            sequence: 0,
+            metrics,
+            pause_requests,
+            pause_requested: false,
+            unreported_drops: 0,
+            last_lag_report: None,
+        })
     }
 
-    fn send_buffer(&mut self, converter: &mut Converter) -> SinkResult<()> {
-        use std::time::{Duration, Instant};
-
-        if self.buffer.is_empty() {
-            return Ok(());
-        }
+    fn send_audio(&mut self, format: AudioFrameFormat, payload: &[u8]) -> SinkResult<()> {
+        let sample_index = self.clock.frames();
+        let decoded_frame =
+            sample_index * u64::from(SAMPLE_RATE) / u64::from(self.output.sample_rate());
+        let position_ms = self.track.position_ms(decoded_frame);
 
-        let now = Instant::now();
+        // Read per frame, a resumed client may have picked a different framing
+        let message = match self.channel.framing() {
+            AudioFraming::Json => {
+                let audio_msg = serde_json::json!({
+                    "type": "audio_data",
+                    "device_id":  &self.device_id,
+                    "data": {
+                        "format": format.label(),
+                        "encoded": BASE64.encode(payload),
+                        "packet_type": format.packet_type(),
+                        "sequence": self.sequence,
+                        "sample_index": sample_index,
+                        "position_ms": position_ms,
+                    }
+                });
 
-        if let Some(last_send) = self.last_send_time {
-            let elapsed = now.duration_since(last_send);
-            let target_duration = Duration::from_millis(100);
+                match serde_json::to_string(&audio_msg) {
+                    Ok(msg) => Message::text(msg),
+                    Err(_) => return Ok(()),
+                }
+            }
+            AudioFraming::Binary => {
+                let mut frame = Vec::with_capacity(AUDIO_FRAME_HEADER_LEN + payload.len());
+                frame.push(format as u8);
+                frame.push(0);
+                frame.extend_from_slice(&self.stream_index.to_le_bytes());
+                frame.extend_from_slice(&self.sequence.to_le_bytes());
+                frame.extend_from_slice(&sample_index.to_le_bytes());
+                frame.extend_from_slice(&position_ms.unwrap_or(UNKNOWN_POSITION).to_le_bytes());
+                frame.extend_from_slice(payload);
+                Message::binary(frame)
+            }
+        };
 
-            if elapsed < target_duration {
-                std::thread::sleep(target_duration - elapsed);
+        // Audio is dropped while the client is away so playback keeps its place for a resume
+        match self.channel.send_audio(message) {
+            AudioSendOutcome::Queued => {
+                self.metrics.frame_sent(payload.len());
+                self.pause_requested = false;
+            }
+            AudioSendOutcome::DroppedOldest => {
+                self.metrics.frame_sent(payload.len());
+                self.frame_dropped();
+            }
+            AudioSendOutcome::Pause => {
+                self.frame_dropped();
+                if !self.pause_requested {
+                    self.pause_requested = true;
+                    self.pause_requests.notify_one();
+                }
             }
+            AudioSendOutcome::Closed => {}
         }
+        self.report_lag();
 
-        let s16_samples = converter.f64_to_s16(&self.buffer);
+        // Dropped frames keep their sequence number so clients can see the gap
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(())
+    }
 
-        let byte_len = s16_samples.len() * 2;
-        let mut byte_buffer = vec![0u8; byte_len];
+    fn frame_dropped(&mut self) {
+        self.metrics.frame_dropped();
+        self.unreported_drops += 1;
+    }
 
-        for (i, &sample) in s16_samples.iter().enumerate() {
-            let bytes = sample.to_le_bytes();
-            byte_buffer[i * 2] = bytes[0];
-            byte_buffer[i * 2 + 1] = bytes[1];
+    /// Tells the client about dropped frames, at most once per [`LAG_REPORT_INTERVAL`].
+    fn report_lag(&mut self) {
+        if self.unreported_drops == 0
+            || self
+                .last_lag_report
+                .is_some_and(|last| last.elapsed() < LAG_REPORT_INTERVAL)
+        {
+            return;
         }
 
-        let encoded = BASE64.encode(&byte_buffer);
-
-        let audio_msg = serde_json::json!({
-            "type": "audio_data",
-            "device_id":  &self.device_id,
+        let lag_msg = serde_json::json!({
+            "type": "stream_lagging",
+            "device_id": &self.device_id,
             "data": {
-                "format": "pcm_s16le",
-                "encoded": encoded,
-                "packet_type": "samples",
+                "stream_index": self.stream_index,
+                "dropped_frames": self.unreported_drops,
+                "policy": self.channel.overflow_policy(),
             }
         });
+        self.channel.send_json(&lag_msg);
 
-        if let Ok(msg) = serde_json::to_string(&audio_msg) {
-            if self.sender.send(Ok(Message::text(msg))).is_err() {
-                return Err(SinkError::NotConnected(
-                    "Failed to send audio data to WebSocket clients".to_string(),
-                ));
+        self.unreported_drops = 0;
+        self.last_lag_report = Some(Instant::now());
+    }
+
+    /// Blocking here is what holds the player back to real time once the lead is sent.
+    fn wait_for_clock(&mut self) {
+        let delay = self.clock.delay(Instant::now());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+            self.metrics.paced(delay);
+        }
+    }
+
+    /// Moves the clock along with the granule position of the Ogg pages sent in passthrough.
+    fn ogg_pages_sent(&mut self, granule: u64) {
+        if let Some(last) = self.last_granule {
+            if granule > last && granule - last <= MAX_GRANULE_STEP {
+                self.clock.advance(granule - last);
+                self.track.advance(granule - last);
             }
         }
+        self.last_granule = Some(granule);
+    }
 
-        self.last_send_time = Some(now);
-        self.buffer.clear();
+    /// Reads the player events sent so far, they apply from the next packet on.
+    ///
+    /// The player event task forwards the same events as `player_event`s, `track_boundary`
+    /// markers carry their `play_request_id` so a client can match the two up.
+    fn poll_player_events(&mut self) {
+        let mut player_events = self.player_events.lock().unwrap();
+        if let Some(player_events) = player_events.as_mut() {
+            while let Ok(event) = player_events.try_recv() {
+                self.track.apply(&event);
+            }
+        }
+    }
+
+    /// Sends a `track_boundary` marker for every track that starts before output sample
+    /// `end`. They are queued as stream markers, so they arrive right before the frame the
+    /// track starts in and are never dropped.
+    fn send_track_boundaries(&mut self, end: u64) {
+        let decoder_rate = u64::from(SAMPLE_RATE);
+        let output_rate = u64::from(self.output.sample_rate());
+        // Decoded frames up to the one output sample `end - 1` is interpolated at
+        let until = (end - 1) * decoder_rate / output_rate + 1;
+
+        while let Some(boundary) = self.track.take_boundary_before(until) {
+            let marker = serde_json::json!({
+                "type": "track_boundary",
+                "device_id": &self.device_id,
+                "data": {
+                    "stream_index": self.stream_index,
+                    "play_request_id": boundary.play_request_id,
+                    "track_id": boundary.track_id,
+                    "sample_index": (boundary.frame * output_rate).div_ceil(decoder_rate),
+                    "position_ms": boundary.position_ms,
+                }
+            });
+            if !self.channel.send_marker(&marker) {
+                warn!(
+                    "Failed to send track boundary of device {}, its client is disconnected",
+                    self.device_id
+                );
+            }
+        }
+    }
+
+    /// Sends one frame of `frame_duration_ms` from the front of the buffer.
+    fn send_buffer(&mut self, converter: &mut Converter) -> SinkResult<()> {
+        self.wait_for_clock();
+        self.send_track_boundaries(self.clock.frames() + self.output.frame_samples() as u64);
+
+        let samples: Vec<f64> = self.buffer.drain(..self.chunk_size).collect();
+        match self.encoder.encode(&samples, converter) {
+            Ok(payload) => self.send_audio(self.encoder.frame_format(), &payload)?,
+            // Skipped like a dropped frame, the client sees the gap in sequence numbers
+            Err(e) => {
+                warn!("Failed to encode audio frame: {}", e);
+                self.sequence = self.sequence.wrapping_add(1);
+            }
+        }
+
+        self.clock.advance(self.output.frame_samples() as u64);
         Ok(())
     }
 }
@@ -109,37 +711,41 @@ impl Sink for WebSocketSink {
     fn start(&mut self) -> SinkResult<()> {
         self.is_active = true;
         self.buffer.clear();
-        self.last_send_time = None;
-
-        let (sample_rate, channels) = match self.format {
-            AudioFormat::F64
-            | AudioFormat::F32
-            | AudioFormat::S32
-            | AudioFormat::S24
-            | AudioFormat::S24_3
-            | AudioFormat::S16 => (44100, 2),
-        };
+        if let Some(resampler) = &mut self.resampler {
+            resampler.reset();
+        }
+        self.encoder.reset();
+        self.clock.reset();
+        self.track.restart();
+        self.last_granule = None;
+        self.sequence = 0;
+
+        let mut info = serde_json::Map::new();
+        info.insert("framing".into(), serde_json::json!(self.channel.framing()));
+        info.insert("stream_index".into(), self.stream_index.into());
+        self.encoder.describe(&mut info);
+        info.insert("sample_rate".into(), self.output.sample_rate().into());
+        info.insert("channels".into(), self.output.channels().into());
+        // Ogg pages don't have a fixed duration
+        if self.output.encoding != Encoding::Passthrough {
+            info.insert(
+                "frame_duration_ms".into(),
+                self.output.frame_duration_ms().into(),
+            );
+        }
+        info.insert(
+            "lead_ms".into(),
+            (self.clock.lead().as_millis() as u64).into(),
+        );
 
         let format_info = serde_json::json!({
             "type": "audio_format",
             "device_id":  &self.device_id,
-            "data": {
-                "sample_rate": sample_rate,
-                "channels": channels,
-                "bit_depth": match self.format {
-                    AudioFormat::S16 => 16,
-                    AudioFormat::S24 | AudioFormat::S24_3 => 24,
-                    AudioFormat::S32 => 32,
-                    AudioFormat::F32 => 32,
-                    AudioFormat::F64 => 64,
-                },
-                "format": format!("{:?}", self.format),
-            }
+            "data": info,
         });
 
-        if let Ok(msg) = serde_json::to_string(&format_info) {
-            let _ = self.sender.send(Ok(Message::text(msg)));
-        }
+        // Behind the previous stream's queued frames, so the format applies from the next one
+        self.channel.start_stream(&self.device_id, &format_info);
 
         Ok(())
     }
@@ -147,7 +753,11 @@ impl Sink for WebSocketSink {
     fn stop(&mut self) -> SinkResult<()> {
         self.is_active = false;
         self.buffer.clear();
-        self.last_send_time = None;
+        if let Some(resampler) = &mut self.resampler {
+            resampler.reset();
+        }
+        self.clock.reset();
+        self.track.restart();
 
         let stop_msg = serde_json::json!({
             "type": "audio_stream_stopped",
@@ -155,44 +765,39 @@ impl Sink for WebSocketSink {
             "data": {}
         });
 
-        if let Ok(msg) = serde_json::to_string(&stop_msg) {
-            let _ = self.sender.send(Ok(Message::text(msg)));
-        }
+        self.channel.stop_stream(&self.device_id, &stop_msg);
 
         Ok(())
     }
 
     fn write(&mut self, packet: AudioPacket, converter: &mut Converter) -> SinkResult<()> {
+        // Also while inactive, so the track position stays current
+        self.poll_player_events();
         if !self.is_active {
             return Ok(());
         }
 
         match &packet {
             AudioPacket::Samples(samples) => {
-                self.buffer.extend_from_slice(samples);
+                self.track
+                    .advance((samples.len() / usize::from(NUM_CHANNELS)) as u64);
+                self.mixed.clear();
+                self.mixer.mix(samples, &mut self.mixed);
+                match &mut self.resampler {
+                    Some(resampler) => resampler.process(&self.mixed, &mut self.buffer),
+                    None => self.buffer.extend_from_slice(&self.mixed),
+                }
 
-                if self.buffer.len() >= self.chunk_size {
+                while self.buffer.len() >= self.chunk_size {
                     self.send_buffer(converter)?;
                 }
             }
-            AudioPacket::Raw(raw_data) => {
-                let encoded = BASE64.encode(raw_data);
-                let audio_msg = serde_json::json!({
-                    "type": "audio_data",
-                    "device_id":  &self.device_id,
-                    "data": {
-                        "format": "pcm_s16le",
-                        "encoded": encoded,
-                        "packet_type": "raw",
-                    }
-                });
-
-                if let Ok(msg) = serde_json::to_string(&audio_msg) {
-                    if self.sender.send(Ok(Message::text(msg))).is_err() {
-                        return Err(SinkError::NotConnected(
-                            "Failed to send audio data to WebSocket clients".to_string(),
-                        ));
-                    }
+            AudioPacket::Raw(pages) => {
+                self.wait_for_clock();
+                self.send_track_boundaries(self.clock.frames() + 1);
+                self.send_audio(AudioFrameFormat::OggVorbis, pages)?;
+                if let Some(granule) = last_granule_position(pages) {
+                    self.ogg_pages_sent(granule);
                 }
             }
         }
@@ -201,10 +806,151 @@ impl Sink for WebSocketSink {
     }
 }
 
+/// Granule position of the last page in `pages` that has one, i.e. the sample count the
+/// stream has reached. The player hands over whole Ogg pages.
+fn last_granule_position(mut pages: &[u8]) -> Option<u64> {
+    const PAGE_HEADER_LEN: usize = 27;
+
+    let mut granule = None;
+    while pages.len() >= PAGE_HEADER_LEN && pages.starts_with(b"OggS") {
+        let position = i64::from_le_bytes(pages[6..14].try_into().unwrap());
+        // -1 on pages where no packet ends
+        if position >= 0 {
+            granule = Some(position as u64);
+        }
+
+        let segments = usize::from(pages[26]);
+        let Some(lacing) = pages.get(PAGE_HEADER_LEN..PAGE_HEADER_LEN + segments) else {
+            break;
+        };
+        let body_len: usize = lacing.iter().map(|&len| usize::from(len)).sum();
+        pages = pages
+            .get(PAGE_HEADER_LEN + segments + body_len..)
+            .unwrap_or_default();
+    }
+    granule
+}
+
 pub fn create_ws_sink(
-    sender: mpsc::UnboundedSender<WsResult<Message>>,
-    format: AudioFormat,
+    channel: ClientChannel,
+    output: OutputSpec,
     device_id: String,
-) -> Box<dyn Sink> {
-    Box::new(WebSocketSink::with_sender(sender, format, device_id))
+    stream_index: u16,
+    pause_requests: Arc<Notify>,
+    lead: Duration,
+    player_events: SinkPlayerEvents,
+) -> Result<Box<dyn Sink + Send>, String> {
+    Ok(Box::new(WebSocketSink::with_channel(
+        channel,
+        output,
+        device_id,
+        stream_index,
+        pause_requests,
+        lead,
+        player_events,
+    )?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ogg page with `granule` and one segment per entry of `segments`.
+    fn ogg_page(granule: i64, segments: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.extend_from_slice(&[0, 0]);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&[0; 12]);
+        page.push(segments.len() as u8);
+        page.extend_from_slice(segments);
+        for &len in segments {
+            page.extend(std::iter::repeat_n(0xAA, usize::from(len)));
+        }
+        page
+    }
+
+    #[test]
+    fn granule_of_the_last_page() {
+        let mut pages = ogg_page(1024, &[255, 10]);
+        pages.extend(ogg_page(4096, &[3]));
+        pages.extend(ogg_page(8192, &[]));
+        assert_eq!(last_granule_position(&pages), Some(8192));
+    }
+
+    #[test]
+    fn skips_pages_without_a_granule() {
+        let mut pages = ogg_page(2048, &[100]);
+        pages.extend(ogg_page(-1, &[255]));
+        assert_eq!(last_granule_position(&pages), Some(2048));
+        assert_eq!(last_granule_position(&ogg_page(-1, &[255])), None);
+    }
+
+    #[test]
+    fn stops_at_truncated_or_foreign_data() {
+        let mut pages = ogg_page(1000, &[20]);
+        let mut truncated = ogg_page(2000, &[200, 200]);
+        truncated.truncate(40);
+        pages.extend(truncated);
+        // The truncated page's header is whole, so its granule still counts
+        assert_eq!(last_granule_position(&pages), Some(2000));
+
+        // Lacing values cut off
+        let mut pages = ogg_page(1000, &[20]);
+        pages.extend(&ogg_page(2000, &[1, 2, 3])[..28]);
+        assert_eq!(last_granule_position(&pages), Some(2000));
+
+        // Header cut off
+        let mut pages = ogg_page(1000, &[20]);
+        pages.extend(&ogg_page(2000, &[1])[..20]);
+        assert_eq!(last_granule_position(&pages), Some(1000));
+
+        assert_eq!(
+            last_granule_position(b"not an ogg page at all, really"),
+            None
+        );
+        assert_eq!(last_granule_position(&[]), None);
+    }
+
+    fn opus(sample_rate: u32, channels: u8) -> OutputSpec {
+        OutputSpec {
+            encoding: Encoding::Opus,
+            sample_rate: Some(sample_rate),
+            channels: Some(channels),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn opus_output_at_every_opus_rate() {
+        for sample_rate in OPUS_SAMPLE_RATES {
+            for channels in [1, 2] {
+                assert_eq!(opus(sample_rate, channels).validate(), Ok(()));
+            }
+        }
+        assert!(opus(44_100, 2).validate().is_err());
+    }
+
+    #[test]
+    fn opus_bitrate_and_complexity_bounds() {
+        let with = |bitrate, complexity| OutputSpec {
+            bitrate: Some(bitrate),
+            complexity: Some(complexity),
+            ..opus(48_000, 2)
+        };
+
+        assert_eq!(with(*OPUS_BITRATES.start(), 0).validate(), Ok(()));
+        assert_eq!(with(*OPUS_BITRATES.end(), 10).validate(), Ok(()));
+        assert!(with(OPUS_BITRATES.start() - 1, 10).validate().is_err());
+        assert!(with(OPUS_BITRATES.end() + 1, 10).validate().is_err());
+        assert!(with(64_000, 11).validate().is_err());
+    }
+
+    #[test]
+    fn bitrate_and_complexity_only_apply_to_opus() {
+        let pcm = OutputSpec {
+            complexity: Some(5),
+            ..Default::default()
+        };
+        assert!(pcm.validate().is_err());
+    }
 }
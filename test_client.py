@@ -8,7 +8,6 @@ import wave
 import io
 import numpy as np
 from datetime import datetime
-import time
 import struct
 
 logging.basicConfig(level=logging.INFO)
@@ -18,45 +17,28 @@ logger = logging.getLogger(__name__)
 current_audio_format = None
 output_file = None
 frames = []
-last_write_time = None
 samples_written = 0
+next_sequence = 0
 
-def calculate_packet_duration(decoded_data):
-    # Calculate duration of the packet in seconds
-    # For S16LE format, each sample is 2 bytes and we have multiple channels
-    bytes_per_frame = 2 * current_audio_format['channels']
-    num_frames = len(decoded_data) // bytes_per_frame
-    return num_frames / current_audio_format['sample_rate']
-
-def should_write_packet(packet_duration):
-    global last_write_time, samples_written
-    
-    current_time = time.time()
-    
-    if last_write_time is None:
-        last_write_time = current_time
-        return True
-        
-    # Calculate expected time based on samples written
-    expected_duration = samples_written / current_audio_format['sample_rate']
-    actual_duration = current_time - last_write_time
-    
-    # If we're writing too fast, wait
-    if actual_duration < expected_duration:
-        time.sleep(expected_duration - actual_duration)
-    
-    return True
+def fill_gap(sample_index):
+    # Dropped frames leave a gap in sample indexes, fill it with silence to stay in sync
+    global samples_written
+    missing = sample_index - samples_written
+    if missing > 0 and output_file:
+        bytes_per_frame = 2 * current_audio_format['channels']
+        output_file.writeframes(b"\0" * (missing * bytes_per_frame))
+        samples_written += missing
 
 def init_wave_file():
-    global output_file, last_write_time, samples_written
+    global output_file, samples_written, next_sequence
     timestamp = datetime.now().strftime("%Y%m%d_%H%M%S")
     filename = f"output_{timestamp}.wav"
     output_file = wave.open(filename, 'wb')
     output_file.setnchannels(current_audio_format['channels'])
     output_file.setsampwidth(current_audio_format['bit_depth'] // 8)
     output_file.setframerate(current_audio_format['sample_rate'])
-    last_write_time = None
     samples_written = 0
+    next_sequence = 0
     logger.info(f"Created new WAV file: {filename}")
 
 async def send_command(websocket, command):
@@ -64,7 +46,7 @@ async def send_command(websocket, command):
     await websocket.send(json.dumps(command))
 
 async def handle_message(message):
-    global current_audio_format, output_file, samples_written
+    global current_audio_format, output_file, samples_written, next_sequence
     data = json.loads(message)
     
     if "type" in data:
@@ -72,9 +54,6 @@ async def handle_message(message):
         if msg_type == "sink_event":
             status = data["data"]["status"]
             logger.info(f"🔊 Sink Event: {status}")
-            if "Stopped" in status:
-                samples_written = 0
-                last_write_time = None
             
         elif msg_type == "player_event":
             event_type = data["data"]["event_type"]
@@ -95,21 +74,29 @@ async def handle_message(message):
                 output_file.close()
             init_wave_file()
             
+        elif msg_type == "track_boundary":
+            boundary = data["data"]
+            logger.info(f"⏭️ Track {boundary['track_id']} starts at sample {boundary['sample_index']}")
+
         elif msg_type == "audio_data":
             if not current_audio_format:
                 logger.warning("Received audio data before format information")
                 return
                 
             audio_data = data["data"]
+            sequence = audio_data["sequence"]
+            if sequence != next_sequence:
+                logger.warning(f"Missed {sequence - next_sequence} audio frames")
+            next_sequence = sequence + 1
+
             if audio_data["format"] == "pcm_s16le":
                 # Decode base64 audio data
                 decoded_data = base64.b64decode(audio_data["encoded"])
-                packet_duration = calculate_packet_duration(decoded_data)
-                
-                if should_write_packet(packet_duration):
-                    if output_file:
-                        output_file.writeframes(decoded_data)
-                        samples_written += len(decoded_data) // (2 * current_audio_format['channels'])
+                fill_gap(audio_data["sample_index"])
+                if output_file:
+                    output_file.writeframes(decoded_data)
+                    samples_written += len(decoded_data) // (2 * current_audio_format['channels'])
+                logger.debug(f"Track position: {audio_data['position_ms']} ms")
                 
     else:
         # This is a command response